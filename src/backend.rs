@@ -0,0 +1,95 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{process, shape::Shape, Size, State};
+
+/// Linear tints of a shape's main and detail sprites
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Tint {
+    pub main: [f32; 3],
+    pub detail: [f32; 3],
+}
+
+/// A renderer the optimizer can run on, either the wgpu `State` or the rayon based `CpuState`
+///
+/// All colors are linear, tints are only valid until the next `test_diff` call
+pub trait Backend {
+    fn target_size(&self) -> &Size;
+
+    /// Fills the whole output canvas with one color
+    fn clear(&mut self, color: [f32; 3]);
+
+    /// Estimates a tint for every shape and returns how much pasting it would change the error,
+    /// negative values are improvements
+    fn test_diff(&mut self, shapes: &[Shape]) -> Vec<i32>;
+
+    /// The tint estimated for the shape at `index` in the last `test_diff` call
+    fn get_tint(&mut self, index: usize) -> Tint;
+
+    /// Colors `test_diff` snaps its tints to before taking the diff, empty to leave them free
+    ///
+    /// `get_tint` still gives the unsnapped tint, `palette::nearest` picks the color
+    fn set_palette(&mut self, palette: &[[f32; 3]]);
+
+    /// Draws a shape onto the output canvas with a tint, usually one returned by `get_tint`
+    fn paste(&mut self, shape: &Shape, tint: Tint);
+
+    /// Saves the output canvas as an image, fails if it can't be written
+    fn render(&mut self, path: &Path) -> anyhow::Result<()>;
+}
+
+impl Backend for State {
+    fn target_size(&self) -> &Size {
+        &self.target_size
+    }
+
+    fn clear(&mut self, color: [f32; 3]) {
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+
+        {
+            let _render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Render Pass"),
+                color_attachments: &[wgpu::RenderPassColorAttachment {
+                    view: &self.output_texture.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color {
+                            r: color[0] as f64,
+                            g: color[1] as f64,
+                            b: color[2] as f64,
+                            a: 1.0,
+                        }),
+
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: None,
+            });
+        }
+
+        self.queue.submit(Some(encoder.finish()));
+    }
+
+    fn test_diff(&mut self, shapes: &[Shape]) -> Vec<i32> {
+        process::test_diff(self, shapes)
+    }
+
+    fn get_tint(&mut self, index: usize) -> Tint {
+        pollster::block_on(process::get_tint(self, index))
+    }
+
+    fn set_palette(&mut self, palette: &[[f32; 3]]) {
+        self.palette = palette.to_vec();
+    }
+
+    fn paste(&mut self, shape: &Shape, tint: Tint) {
+        shape.paste(self, tint);
+    }
+
+    fn render(&mut self, path: &Path) -> anyhow::Result<()> {
+        pollster::block_on(crate::render(self, path))
+    }
+}
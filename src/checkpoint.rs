@@ -0,0 +1,62 @@
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::backend::Tint;
+use crate::config::Config;
+use crate::shape::Shape;
+
+/// Everything needed to pick a run up where it stopped
+///
+/// The random numbers of an iteration only depend on the seed and the iteration, so those
+/// together with the carried over candidates are the whole optimizer state
+#[derive(Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub config: Config,
+    /// The next iteration to run
+    pub iteration: usize,
+    pub error: i64,
+    /// `metric::diff_scale` the error was summed at
+    pub diff_scale: f32,
+    pub last_improvement: usize,
+    /// Time already spent, counted towards the time limit
+    pub elapsed: Duration,
+    /// Objects the palette search placed, counted towards the object limit
+    pub searched: usize,
+    /// Every placed shape in order, replayed to rebuild the canvas and the level
+    pub accepted: Vec<Accepted>,
+    /// Candidates carried over into `iteration`
+    pub population: Vec<Shape>,
+    /// The enabled objects, shapes only refer to them by index
+    pub objects: Vec<u16>,
+    /// The colors of a palette mode run, `None` while it's still looking for them
+    pub palette: Option<Vec<[f32; 3]>>,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct Accepted {
+    pub iteration: usize,
+    pub shape: Shape,
+    pub tint: Tint,
+}
+
+impl Checkpoint {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Checkpoint> {
+        let path = path.as_ref();
+        let data =
+            fs::read_to_string(path).with_context(|| format!("couldn't read {}", path.display()))?;
+        serde_json::from_str(&data).with_context(|| format!("invalid checkpoint {}", path.display()))
+    }
+
+    /// Writes next to the old checkpoint first, so a crash while saving doesn't lose it
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_string(self)?)
+            .with_context(|| format!("couldn't write {}", tmp.display()))?;
+        fs::rename(&tmp, path).with_context(|| format!("couldn't write {}", path.display()))
+    }
+}
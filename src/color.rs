@@ -0,0 +1,137 @@
+use crate::backend::Tint;
+use crate::level::Hsv;
+use crate::lin;
+use crate::shape::to_srgb;
+
+/// The color the generated channels are set to, every tint is an HSV shift of it
+pub const CHANNEL_BASE: [f32; 3] = [1.0, 0.0, 0.0];
+
+/// How far past full brightness a tint can go before it counts as clipped
+const TOLERANCE: f32 = 1.0 / 255.0;
+
+/// The range GD's editor allows for multiplicative saturation and brightness
+const MAX_FACTOR: f32 = 2.0;
+
+/// An HSV adjustment together with whether it had to clip the color it was solved for
+#[derive(Debug, Clone, Copy)]
+pub struct Solved {
+    pub hsv: Hsv,
+    pub clipped: bool,
+}
+
+/// What GD shows for a channel of color `base` shifted by `hsv`, both linear
+///
+/// GD turns the sRGB base color into HSV, shifts the hue, scales or offsets saturation
+/// and brightness, and clamps them before turning it back
+pub fn apply(base: [f32; 3], hsv: &Hsv) -> [f32; 3] {
+    let [r, g, b] = base.map(to_srgb);
+    let (h, s, v) = rgb_to_hsv(r, g, b);
+    let shift = |x: f32, by: f32, additive: bool| {
+        let x = if additive { x + by } else { x * by };
+        x.clamp(0.0, 1.0)
+    };
+    let h = (h + hsv.h).rem_euclid(360.0);
+    let s = shift(s, hsv.s, hsv.s_additive);
+    let v = shift(v, hsv.v, hsv.v_additive);
+    hsv_to_rgb(h, s, v).map(lin)
+}
+
+/// The adjustment that turns a channel of color `base` into `target`, both linear
+///
+/// Saturation and brightness are multiplied like the editor does by default, or offset
+/// when the base has too little of them for that. Anything brighter than full is clipped
+pub fn solve(base: [f32; 3], target: [f32; 3]) -> Solved {
+    let clipped = target.iter().any(|c| *c > 1.0 + TOLERANCE);
+    let [r, g, b] = base.map(to_srgb);
+    let (h0, s0, v0) = rgb_to_hsv(r, g, b);
+    let [r, g, b] = target.map(to_srgb);
+    let (h, s, v) = rgb_to_hsv(r, g, b);
+
+    let shift = |from: f32, to: f32| {
+        if from > 0.0 && to / from <= MAX_FACTOR {
+            (to / from, false)
+        } else {
+            (to - from, true)
+        }
+    };
+    // the editor only takes whole degrees from -180 to 180
+    let mut hue = (h - h0).round().rem_euclid(360.0);
+    if hue > 180.0 {
+        hue -= 360.0;
+    }
+    let (s, s_additive) = shift(s0, s);
+    let (v, v_additive) = shift(v0, v);
+    Solved {
+        hsv: Hsv {
+            h: hue,
+            s,
+            v,
+            s_additive,
+            v_additive,
+        },
+        clipped,
+    }
+}
+
+/// The tint the level really shows for an estimated one, and whether any of it was clipped
+pub fn realize(tint: &Tint) -> (Tint, bool) {
+    let main = solve(CHANNEL_BASE, tint.main);
+    let detail = solve(CHANNEL_BASE, tint.detail);
+    let shown = Tint {
+        main: apply(CHANNEL_BASE, &main.hsv),
+        detail: apply(CHANNEL_BASE, &detail.hsv),
+    };
+    (shown, main.clipped || detail.clipped)
+}
+
+/// The whole sRGB values a color trigger stores for a linear color
+pub fn trigger_rgb(color: [f32; 3]) -> [f32; 3] {
+    color.map(|c| (to_srgb(c) * 255.0).round())
+}
+
+/// The linear color a color trigger really shows when it's set to `color`
+pub fn quantize(color: [f32; 3]) -> [f32; 3] {
+    trigger_rgb(color).map(|c| lin(c / 255.0))
+}
+
+/// Hue in degrees, saturation and value from 0 to 1
+pub(crate) fn rgb_to_hsv(r: f32, g: f32, b: f32) -> (f32, f32, f32) {
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let v = max;
+
+    let d = max - min;
+    let s = if max == 0.0 { 0.0 } else { d / max };
+
+    let h = if max == min {
+        0.0 // achromatic
+    } else {
+        (if max == r {
+            (g - b) / d + (if g < b { 6.0 } else { 0.0 })
+        } else if max == g {
+            (b - r) / d + 2.0
+        } else {
+            (r - g) / d + 4.0
+        }) / 6.0
+    };
+    if h.is_nan() || s.is_nan() || v.is_nan() {
+        panic!("NaN did done with R: {}, G: {}, B: {}", r, g, b)
+    }
+    (h * 360.0, s, v)
+}
+
+pub(crate) fn hsv_to_rgb(h: f32, s: f32, v: f32) -> [f32; 3] {
+    let c = v * s;
+    let h = h / 60.0;
+    let x = c * (1.0 - (h % 2.0 - 1.0).abs());
+    let [r, g, b] = match h as u32 {
+        0 => [c, x, 0.0],
+        1 => [x, c, 0.0],
+        2 => [0.0, c, x],
+        3 => [0.0, x, c],
+        4 => [x, 0.0, c],
+        _ => [c, 0.0, x],
+    };
+    let m = v - c;
+    [r + m, g + m, b + m]
+}
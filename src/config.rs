@@ -0,0 +1,437 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::level::MAX_ID;
+use crate::library::Selection;
+use crate::mask::MaskSource;
+use crate::metric::Metric;
+use crate::palette::MAX_PALETTE;
+use crate::process::TOTAL_SHAPES;
+use crate::placement::Placement;
+use crate::shape::MIN_WIDTH;
+
+pub const USAGE: &str = "\
+usage: gdeometrize [options] <target>
+       gdeometrize --list-levels <CCLocalLevels.dat>
+       gdeometrize --resume <checkpoint>
+
+options:
+  -o, --output <path>        rendered preview image (default: output.png)
+  -l, --level <path>         level string output (default: levelstring.txt)
+      --gmd <path>           also export the level as an importable .gmd file
+      --name <name>          level name in the .gmd (default: the target's file name)
+      --description <text>   level description in the .gmd
+      --creator <name>       creator name in the .gmd
+      --song <id>            official song of the .gmd level (default: 0)
+      --custom-song <id>     newgrounds song of the .gmd level
+      --save-file <path>     also add the level to a CCLocalLevels.dat, replacing one with the same name
+      --append <path>        merge the art into this level string instead of starting an empty level
+      --no-background        leave out the background color trigger, to overlay the art on a level
+      --anchor <x,y>         position of the art's top left corner in GD units (default: 0,0)
+      --scale <f>            scale of the art, 1 places one working pixel every half unit (default: 1)
+      --width-blocks <n>     scale the art to be this many blocks wide instead
+      --rotation <deg>       turn the art clockwise around the anchor
+      --no-flip-y            keep the image's downward y axis, which mirrors the art
+      --uniform-scale        only scale objects evenly, for GD versions before 2.2
+      --position-step <u>    snap object positions to multiples of this in GD units, 0 to
+                             keep them exact (default: 0.01)
+      --rotation-step <deg>  snap object rotations the same way (default: 0.01)
+      --scale-step <f>       snap object scales the same way (default: 0.01)
+      --editor-layer <n>     editor layer for the generated objects
+      --group <id>           group for the generated objects (default: a free one when appending)
+      --objects <path>       manifest of the objects that may be placed (default: objects.json)
+      --include <list>       only place these objects, a comma separated list of ids,
+                             id ranges like 1000-1100 and tags
+      --exclude <list>       never place these objects, in the same format
+      --preset <name>        object selection preset from the manifest, like circles or glow
+      --sheet-cache <dir>    where packed sprite sheets are kept between runs (default: .sheet-cache)
+      --no-sheet-cache       pack the sprite sheet on every run
+      --frames <dir>         save a preview frame for every iteration into <dir>
+      --checkpoint <path>    periodically save the run's progress so it can be resumed
+      --checkpoint-every <n> iterations between checkpoints (default: 100)
+  -n, --shapes <n>           iterations, at most one object is placed per iteration (default: 3000)
+      --max-objects <n>      stop once this many objects are placed
+      --target-error <e>     stop once the mean error per pixel drops to this
+      --time-limit <secs>    stop after this much time
+      --plateau <min,n>      stop when no iteration improved by more than <min> in the last <n>
+  -w, --width <px>           working resolution the optimizer runs at (default: 360)
+      --output-width <px>    resolution of the rendered preview (default: 1024)
+      --opacity <0-1>        opacity of every placed object (default: 0.8)
+      --metric <name>        how color differences are measured, rgb, redmean, cie76,
+                             ciede2000 or oklab (default: redmean)
+      --transparent <mode>   ignore to leave transparent parts of the target out of the fit, or
+                             background to keep them the background color (default: ignore)
+      --edge-weight <w>      also compare the edges of the art with the target's, weighted
+                             against the color difference (default: 0, off)
+      --mask <path|auto>     grayscale image weighting how much every part of the target
+                             counts, white the most, or auto to weight detailed parts up
+      --palette <n>          color the art with at most n color channels instead of giving
+                             every object its own HSV, runs once to find the colors and
+                             once more with them, the first run gets half the time and
+                             object limits
+      --cutoff <n>           best candidates kept between adjustment rounds (default: 32)
+      --passed-on <n>        candidates carried over to the next iteration (default: 600)
+      --adjustments <n>      adjustment rounds per iteration (default: 24)
+      --seed <n>             seed for the random number generator (default: random)
+      --backend <kind>       gpu, cpu or auto to fall back to the cpu without an adapter (default: auto)
+      --resume <path>        continue the run saved in a checkpoint, with the same options
+  -h, --help                 print this message
+";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Config {
+    pub target: PathBuf,
+    pub output: PathBuf,
+    pub level: PathBuf,
+    pub gmd: Option<PathBuf>,
+    pub name: String,
+    pub description: String,
+    pub creator: String,
+    pub song: u32,
+    pub custom_song: Option<u32>,
+    pub save_file: Option<PathBuf>,
+
+    pub append: Option<PathBuf>,
+    /// Leave the background trigger out, appended levels keep their own anyway
+    pub no_background: bool,
+    pub placement: Placement,
+    pub editor_layer: Option<u16>,
+    pub group: Option<u16>,
+
+    pub objects: PathBuf,
+    pub selection: Selection,
+    pub sheet_cache: Option<PathBuf>,
+    pub frames: Option<PathBuf>,
+    pub checkpoint: Option<PathBuf>,
+    pub checkpoint_every: usize,
+
+    pub iterations: usize,
+    pub max_objects: Option<usize>,
+    pub target_error: Option<f64>,
+    pub time_limit: Option<Duration>,
+    /// Minimum improvement and the number of iterations it has to happen within
+    pub plateau: Option<(i32, usize)>,
+    pub width: u32,
+    pub output_width: u32,
+    pub opacity: f32,
+    /// How far the art is from the target
+    pub metric: Metric,
+    /// Weights the parts of the target, every pixel counts the same without it
+    pub mask: Option<MaskSource>,
+    pub transparent: Transparency,
+    /// How much following the target's edges counts next to matching its colors
+    pub edge_weight: f32,
+    /// Most color channels the art may use, tints are free HSV shifts without it
+    pub palette: Option<usize>,
+
+    pub cutoff: usize,
+    pub passed_on: usize,
+    pub adjustments: usize,
+    pub seed: u64,
+
+    pub backend: BackendKind,
+}
+
+pub enum Command {
+    Run(Box<Config>),
+    /// Print the levels inside a save file
+    ListLevels(PathBuf),
+    /// Continue a run from a checkpoint
+    Resume(PathBuf),
+    Help,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BackendKind {
+    Auto,
+    Gpu,
+    Cpu,
+}
+
+impl std::str::FromStr for BackendKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(BackendKind::Auto),
+            "gpu" => Ok(BackendKind::Gpu),
+            "cpu" => Ok(BackendKind::Cpu),
+            _ => bail!("expected gpu, cpu or auto"),
+        }
+    }
+}
+
+/// What the transparent parts of the target stand for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Transparency {
+    /// Anything, they don't count towards the error
+    #[default]
+    Ignore,
+    /// The background, they count like the background color
+    Background,
+}
+
+impl std::str::FromStr for Transparency {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ignore" => Ok(Transparency::Ignore),
+            "background" => Ok(Transparency::Background),
+            _ => bail!("expected ignore or background"),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            target: PathBuf::new(),
+            output: "output.png".into(),
+            level: "levelstring.txt".into(),
+            gmd: None,
+            name: String::new(),
+            description: String::new(),
+            creator: String::new(),
+            song: 0,
+            custom_song: None,
+            save_file: None,
+
+            append: None,
+            no_background: false,
+            placement: Placement::default(),
+            editor_layer: None,
+            group: None,
+
+            objects: "objects.json".into(),
+            selection: Selection::default(),
+            sheet_cache: Some(".sheet-cache".into()),
+            frames: None,
+            checkpoint: None,
+            checkpoint_every: 100,
+
+            iterations: 3000,
+            max_objects: None,
+            target_error: None,
+            time_limit: None,
+            plateau: None,
+            width: 360,
+            output_width: 1024,
+            opacity: 0.8,
+            metric: Metric::Redmean,
+            mask: None,
+            transparent: Transparency::Ignore,
+            edge_weight: 0.0,
+            palette: None,
+
+            cutoff: 32,
+            passed_on: 600,
+            adjustments: 24,
+            seed: rand::random(),
+
+            backend: BackendKind::Auto,
+        }
+    }
+}
+
+impl Config {
+    /// Parses the command line
+    pub fn from_args<I>(args: I) -> Result<Command>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut config = Config::default();
+        let mut target = None;
+        let mut list_levels = None;
+        let mut resume = None;
+        let mut width_blocks = None;
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| {
+                args.next()
+                    .ok_or_else(|| anyhow!("missing value for {name}"))
+            };
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(Command::Help),
+                "--list-levels" => list_levels = Some(value(&arg)?.into()),
+                "--resume" => resume = Some(value(&arg)?.into()),
+                "-o" | "--output" => config.output = value(&arg)?.into(),
+                "-l" | "--level" => config.level = value(&arg)?.into(),
+                "--gmd" => config.gmd = Some(value(&arg)?.into()),
+                "--name" => config.name = value(&arg)?,
+                "--description" => config.description = value(&arg)?,
+                "--creator" => config.creator = value(&arg)?,
+                "--song" => config.song = parse(&arg, value(&arg)?)?,
+                "--custom-song" => config.custom_song = Some(parse(&arg, value(&arg)?)?),
+                "--save-file" => config.save_file = Some(value(&arg)?.into()),
+                "--append" => config.append = Some(value(&arg)?.into()),
+                "--anchor" => config.placement.origin = parse_pair(&arg, value(&arg)?)?,
+                "--scale" => config.placement.scale = parse(&arg, value(&arg)?)?,
+                "--width-blocks" => width_blocks = Some(parse::<f32>(&arg, value(&arg)?)?),
+                "--rotation" => config.placement.rotation = parse(&arg, value(&arg)?)?,
+                "--no-flip-y" => config.placement.flip_y = false,
+                "--no-background" => config.no_background = true,
+                "--uniform-scale" => config.placement.uniform_scale = true,
+                "--position-step" => {
+                    config.placement.precision.position = parse(&arg, value(&arg)?)?
+                }
+                "--rotation-step" => {
+                    config.placement.precision.rotation = parse(&arg, value(&arg)?)?
+                }
+                "--scale-step" => config.placement.precision.scale = parse(&arg, value(&arg)?)?,
+                "--editor-layer" => config.editor_layer = Some(parse(&arg, value(&arg)?)?),
+                "--group" => config.group = Some(parse(&arg, value(&arg)?)?),
+                "--objects" => config.objects = value(&arg)?.into(),
+                "--include" => config.selection.include.extend(parse_list(&arg, value(&arg)?)?),
+                "--exclude" => config.selection.exclude.extend(parse_list(&arg, value(&arg)?)?),
+                "--preset" => config.selection.preset = Some(value(&arg)?),
+                "--sheet-cache" => config.sheet_cache = Some(value(&arg)?.into()),
+                "--no-sheet-cache" => config.sheet_cache = None,
+                "--frames" => config.frames = Some(value(&arg)?.into()),
+                "--checkpoint" => config.checkpoint = Some(value(&arg)?.into()),
+                "--checkpoint-every" => config.checkpoint_every = parse(&arg, value(&arg)?)?,
+                "-n" | "--shapes" => config.iterations = parse(&arg, value(&arg)?)?,
+                "--max-objects" => config.max_objects = Some(parse(&arg, value(&arg)?)?),
+                "--target-error" => config.target_error = Some(parse(&arg, value(&arg)?)?),
+                "--time-limit" => {
+                    let secs = parse(&arg, value(&arg)?)?;
+                    config.time_limit = Some(Duration::try_from_secs_f64(secs)?)
+                }
+                "--plateau" => {
+                    let v = value(&arg)?;
+                    let (min, rounds) = v
+                        .split_once(',')
+                        .ok_or_else(|| anyhow!("expected min,n for {arg}"))?;
+                    config.plateau = Some((
+                        parse(&arg, min.to_string())?,
+                        parse(&arg, rounds.to_string())?,
+                    ));
+                }
+                "-w" | "--width" => config.width = parse(&arg, value(&arg)?)?,
+                "--output-width" => config.output_width = parse(&arg, value(&arg)?)?,
+                "--opacity" => config.opacity = parse(&arg, value(&arg)?)?,
+                "--metric" => config.metric = parse(&arg, value(&arg)?)?,
+                "--transparent" => config.transparent = parse(&arg, value(&arg)?)?,
+                "--mask" => config.mask = Some(parse(&arg, value(&arg)?)?),
+                "--edge-weight" => config.edge_weight = parse(&arg, value(&arg)?)?,
+                "--palette" => config.palette = Some(parse(&arg, value(&arg)?)?),
+                "--cutoff" => config.cutoff = parse(&arg, value(&arg)?)?,
+                "--passed-on" => config.passed_on = parse(&arg, value(&arg)?)?,
+                "--adjustments" => config.adjustments = parse(&arg, value(&arg)?)?,
+                "--seed" => config.seed = parse(&arg, value(&arg)?)?,
+                "--backend" => config.backend = parse(&arg, value(&arg)?)?,
+                _ if arg.starts_with('-') => bail!("unknown option {arg}"),
+                _ if target.is_none() => target = Some(arg.into()),
+                _ => bail!("unexpected argument {arg}"),
+            }
+        }
+
+        if let Some(path) = list_levels {
+            return Ok(Command::ListLevels(path));
+        }
+        if let Some(path) = resume {
+            return Ok(Command::Resume(path));
+        }
+
+        config.target = target.ok_or_else(|| anyhow!("no target image given"))?;
+        if let Some(blocks) = width_blocks {
+            config.placement.scale = Placement::scale_for_width(blocks, config.width);
+        }
+        if config.name.is_empty() {
+            config.name = config
+                .target
+                .file_stem()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_default();
+        }
+        config.validate()?;
+        Ok(Command::Run(Box::new(config)))
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.width < MIN_WIDTH {
+            bail!("width must be at least {MIN_WIDTH} pixels");
+        }
+        if self.output_width == 0 {
+            bail!("output width must be at least 1 pixel");
+        }
+        if !(0.0..=1.0).contains(&self.opacity) {
+            bail!("opacity must be between 0 and 1");
+        }
+        if !(0.0..).contains(&self.edge_weight) {
+            bail!("edge weight can't be negative");
+        }
+        let placement = &self.placement;
+        if !(placement.scale.is_finite() && placement.scale > 0.0) {
+            bail!("scale must be positive");
+        }
+        if !placement.rotation.is_finite() {
+            bail!("rotation must be a finite number of degrees");
+        }
+        if !placement.origin.iter().all(|v| v.is_finite()) {
+            bail!("anchor must be finite");
+        }
+        let precision = self.placement.precision;
+        if [precision.position, precision.rotation, precision.scale]
+            .iter()
+            .any(|step| !(0.0..).contains(step))
+        {
+            bail!("precision steps can't be negative");
+        }
+        if matches!(self.group, Some(g) if g == 0 || g > MAX_ID) {
+            bail!("group must be between 1 and {MAX_ID}");
+        }
+        if self.palette.is_some_and(|n| n == 0 || n > MAX_PALETTE) {
+            bail!("palette must have between 1 and {MAX_PALETTE} colors");
+        }
+        if self.checkpoint_every == 0 {
+            bail!("checkpoint-every must be at least 1");
+        }
+        if self.adjustments == 0 {
+            bail!("at least one adjustment round is needed");
+        }
+        // every kept candidate spawns TOTAL_SHAPES / cutoff - 2 mutations
+        if self.cutoff == 0 || self.cutoff > TOTAL_SHAPES / 3 {
+            bail!("cutoff must be between 1 and {}", TOTAL_SHAPES / 3);
+        }
+        if self.passed_on >= TOTAL_SHAPES {
+            bail!("passed-on must be less than {TOTAL_SHAPES}");
+        }
+        Ok(())
+    }
+}
+
+fn parse<T>(name: &str, value: String) -> Result<T>
+where
+    T: std::str::FromStr,
+    T::Err: Into<anyhow::Error>,
+{
+    value
+        .parse()
+        .map_err(Into::<anyhow::Error>::into)
+        .with_context(|| format!("invalid value {value:?} for {name}"))
+}
+
+/// Comma separated values
+fn parse_list<T>(name: &str, value: String) -> Result<Vec<T>>
+where
+    T: std::str::FromStr,
+    T::Err: Into<anyhow::Error>,
+{
+    value
+        .split(',')
+        .map(|v| parse(name, v.trim().to_string()))
+        .collect()
+}
+
+/// `x,y`
+fn parse_pair(name: &str, value: String) -> Result<[f32; 2]> {
+    let (x, y) = value
+        .split_once(',')
+        .ok_or_else(|| anyhow!("expected x,y for {name}"))?;
+    Ok([parse(name, x.to_string())?, parse(name, y.to_string())?])
+}
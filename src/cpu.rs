@@ -0,0 +1,458 @@
+use std::path::Path;
+
+use anyhow::Context;
+use image::{GrayImage, Rgba32FImage, RgbaImage};
+use rayon::prelude::*;
+
+use crate::{
+    backend::{Backend, Tint},
+    config::Config,
+    lin,
+    metric::{self, Metric},
+    palette,
+    shape::{to_srgb, Shape, Sheet},
+    Size, Vertex,
+};
+
+/// Fixed point factor the shader uses for its atomic sums
+const FACTOR: f32 = 1000.0;
+
+/// Runs the passes from `shader.wgsl` with rayon, used when there is no GPU adapter
+pub struct CpuState {
+    sheet: Sheet,
+    // the gpu samples the sheet through an srgb texture, so this is stored linear, one
+    // page after the other
+    sheet_pixels: Vec<[f32; 4]>,
+    target: Vec<[f32; 3]>,
+    /// How much every target pixel counts
+    mask: Vec<f32>,
+    target_size: Size,
+    output: Vec<[f32; 3]>,
+    output_size: Size,
+    opacity: f32,
+    metric: Metric,
+    edge_weight: f32,
+    /// `metric::diff_scale`
+    diff_scale: f32,
+    tints: Vec<[TintSum; 2]>,
+    palette: Vec<[f32; 3]>,
+}
+
+/// One entry of the shader's tint buffer
+#[derive(Debug, Clone, Copy, Default)]
+struct TintSum {
+    tint: [u32; 3],
+    count: u32,
+}
+
+impl TintSum {
+    fn color(&self) -> [f32; 3] {
+        self.tint.map(|x| x as f32 / self.count.max(1) as f32)
+    }
+
+    fn add(&mut self, tex: [f32; 4], a: f32, target: [f32; 3], fac: f32) {
+        self.count = self.count.wrapping_add((a * FACTOR * fac) as u32);
+        for c in 0..3 {
+            let f = (tex[c] > 0.0) as u32 as f32;
+            let t = f * a * (target[c] / tex[c]) * FACTOR * fac;
+            self.tint[c] = self.tint[c].wrapping_add(t as u32);
+        }
+    }
+}
+
+impl CpuState {
+    pub fn new(
+        target: &Rgba32FImage,
+        mask: &GrayImage,
+        output_size: Size,
+        sheet: Sheet,
+        config: &Config,
+    ) -> CpuState {
+        let sheet_pixels = sheet
+            .pages
+            .iter()
+            .flat_map(|page| page.pixels())
+            .map(|p| {
+                [
+                    lin(p[0] as f32 / 255.0),
+                    lin(p[1] as f32 / 255.0),
+                    lin(p[2] as f32 / 255.0),
+                    p[3] as f32 / 255.0,
+                ]
+            })
+            .collect();
+        let target_size = Size {
+            width: target.width(),
+            height: target.height(),
+        };
+        let target = target.pixels().map(|p| [p[0], p[1], p[2]]).collect();
+        let diff_scale = metric::diff_scale(config, &target_size);
+
+        CpuState {
+            sheet,
+            sheet_pixels,
+            target,
+            mask: mask.pixels().map(|p| p[0] as f32 / 255.0).collect(),
+            target_size,
+            output: vec![[0.0; 3]; (output_size.width * output_size.height) as usize],
+            output_size,
+            opacity: config.opacity,
+            metric: config.metric,
+            edge_weight: config.edge_weight,
+            diff_scale,
+            tints: Vec::new(),
+            palette: Vec::new(),
+        }
+    }
+
+    /// `fs_find_avg_color` followed by `fs_find_diff` for a single shape
+    fn shape_diff(&self, shape: &Shape) -> ([TintSum; 2], i32) {
+        let size = &self.target_size;
+        let verts = shape.get_vertices(&self.sheet, size, 0);
+        let sprite = Sprite::of(&verts[0]);
+
+        let mut main = TintSum::default();
+        let mut detail = TintSum::default();
+        rasterize(&verts, size, size, |_, coords, _| {
+            let weight = self.mask[texel(coords.target, size.width, size.height)];
+            let fac = inside(coords.target) as u32 as f32 * weight;
+            let tex = self.sheet_texel(sprite.page, coords.tex);
+            let detail_tex = self.detail_texel(sprite, coords.detail);
+            let target = self.target_texel(coords.target);
+
+            main.add(tex, tex[3] * (1.0 - detail_tex[3]), target, fac);
+            detail.add(detail_tex, detail_tex[3], target, fac);
+        });
+
+        if main.count == 0 && detail.count == 0 {
+            return ([main, detail], 0);
+        }
+        let snap = |color: [f32; 3]| {
+            if self.palette.is_empty() {
+                color
+            } else {
+                self.palette[palette::nearest(&self.palette, color)]
+            }
+        };
+        let tint = Tint {
+            main: snap(main.color()),
+            detail: snap(detail.color()),
+        };
+
+        let mut diff = 0i32;
+        rasterize(&verts, size, size, |_, coords, steps| {
+            if !inside(coords.target) {
+                return;
+            }
+            let target = self.target_texel(coords.target);
+            let current = self.output_texel(coords.target);
+            let next = self.blend(&tint, sprite, coords, current);
+
+            let mut d = self.metric.diff(target, next) - self.metric.diff(target, current);
+            if self.edge_weight > 0.0 && self.interior(coords.target) {
+                let lumas = |o| self.lumas(&tint, sprite, coords.offset(steps, o));
+                let (right, left) = (lumas([1.0, 0.0]), lumas([-1.0, 0.0]));
+                let (down, up) = (lumas([0.0, 1.0]), lumas([0.0, -1.0]));
+                let gx = [0, 1, 2].map(|i| (right[i] - left[i]) / 2.0);
+                let gy = [0, 1, 2].map(|i| (down[i] - up[i]) / 2.0);
+                let edges = |i: usize| (gx[0] - gx[i]).hypot(gy[0] - gy[i]);
+                d += self.edge_weight * (edges(2) - edges(1));
+            }
+            let weight = self.mask[texel(coords.target, size.width, size.height)];
+            diff = diff.wrapping_add(metric::fixed(d * weight, self.diff_scale));
+        });
+
+        ([main, detail], diff)
+    }
+
+    /// The detail sprite drawn over the main one, drawn over `current`
+    fn blend(&self, tint: &Tint, sprite: Sprite, coords: Coords, current: [f32; 3]) -> [f32; 3] {
+        let tex = self.sheet_texel(sprite.page, coords.tex);
+        let a = tex[3] * self.opacity;
+        let next = [0, 1, 2].map(|c| tex[c] * tint.main[c] * a + current[c] * (1.0 - a));
+
+        let tex = self.detail_texel(sprite, coords.detail);
+        let a = tex[3] * self.opacity;
+        [0, 1, 2].map(|c| tex[c] * tint.detail[c] * a + next[c] * (1.0 - a))
+    }
+
+    /// Same as `lumas` in the shader
+    fn lumas(&self, tint: &Tint, sprite: Sprite, coords: Coords) -> [f32; 3] {
+        let target = self.target_texel(coords.target);
+        let current = self.output_texel(coords.target);
+        let r = sprite.tex_rect;
+        let inside = (0..2).all(|i| coords.tex[i] > r[i] && coords.tex[i] < r[i] + r[i + 2]);
+        let next = match inside {
+            true => self.blend(tint, sprite, coords, current),
+            false => current,
+        };
+        [target, current, next].map(luma)
+    }
+
+    /// Whether the pixel at `uv` has neighbours on all sides
+    fn interior(&self, uv: [f32; 2]) -> bool {
+        let size = [self.target_size.width, self.target_size.height];
+        (0..2).all(|i| (uv[i] - 0.5).abs() < 0.5 - 1.0 / size[i] as f32)
+    }
+
+    fn sheet_texel(&self, page: i32, uv: [f32; 2]) -> [f32; 4] {
+        let [w, h] = self.sheet.size;
+        self.sheet_pixels[page as usize * (w * h) as usize + texel(uv, w, h)]
+    }
+
+    /// Same as `sample_detail` in the shader
+    fn detail_texel(&self, sprite: Sprite, uv: [f32; 2]) -> [f32; 4] {
+        let rect = sprite.detail_rect;
+        let inside = rect[2] > 0.0 && inside(uv);
+        let uv = [0, 1].map(|i| rect[i] + uv[i].clamp(0.0, 1.0) * rect[i + 2]);
+        self.sheet_texel(sprite.detail_page, uv)
+            .map(|c| c * inside as u32 as f32)
+    }
+
+    fn target_texel(&self, uv: [f32; 2]) -> [f32; 3] {
+        self.target[texel(uv, self.target_size.width, self.target_size.height)]
+    }
+
+    fn output_texel(&self, uv: [f32; 2]) -> [f32; 3] {
+        self.output[texel(uv, self.output_size.width, self.output_size.height)]
+    }
+}
+
+impl Backend for CpuState {
+    fn target_size(&self) -> &Size {
+        &self.target_size
+    }
+
+    fn clear(&mut self, color: [f32; 3]) {
+        self.output.fill(color);
+    }
+
+    fn test_diff(&mut self, shapes: &[Shape]) -> Vec<i32> {
+        let (tints, diffs) = shapes
+            .par_iter()
+            .map(|shape| self.shape_diff(shape))
+            .unzip();
+        self.tints = tints;
+        diffs
+    }
+
+    fn get_tint(&mut self, index: usize) -> Tint {
+        let [main, detail] = self.tints[index];
+        Tint {
+            main: main.color(),
+            detail: detail.color(),
+        }
+    }
+
+    fn set_palette(&mut self, palette: &[[f32; 3]]) {
+        self.palette = palette.to_vec();
+    }
+
+    fn paste(&mut self, shape: &Shape, tint: Tint) {
+        let size = &self.target_size;
+        let verts = shape.get_vertices(&self.sheet, size, 0);
+        let sprite = Sprite::of(&verts[0]);
+        let mut output = std::mem::take(&mut self.output);
+        rasterize(&verts, &self.output_size, size, |i, coords, _| {
+            output[i] = self.blend(&tint, sprite, coords, output[i]);
+        });
+        self.output = output;
+    }
+
+    fn render(&mut self, path: &Path) -> anyhow::Result<()> {
+        let data = self
+            .output
+            .iter()
+            .flat_map(|c| {
+                let [r, g, b] = c.map(|v| (to_srgb(v) * 255.0).round() as u8);
+                [r, g, b, 255]
+            })
+            .collect();
+        RgbaImage::from_raw(self.output_size.width, self.output_size.height, data)
+            .unwrap()
+            .save(path)
+            .with_context(|| format!("couldn't write {}", path.display()))
+    }
+}
+
+/// Difference between the target and a canvas filled with `color`, summed the same way
+/// the diff pass sums up improvements
+///
+/// The flat canvas has no edges, so the edge difference is just how steep the target is
+pub fn image_error(
+    target: &Rgba32FImage,
+    mask: &GrayImage,
+    color: [f32; 3],
+    config: &Config,
+) -> i64 {
+    let pixel = |x: u32, y: u32| {
+        let p = target.get_pixel(x, y);
+        [p[0], p[1], p[2]]
+    };
+    let (w, h) = target.dimensions();
+    let size = Size {
+        width: w,
+        height: h,
+    };
+    let scale = metric::diff_scale(config, &size);
+    (0..h)
+        .flat_map(|y| (0..w).map(move |x| (x, y)))
+        .map(|(x, y)| {
+            let mut d = config.metric.diff(pixel(x, y), color);
+            if config.edge_weight > 0.0 && (1..w - 1).contains(&x) && (1..h - 1).contains(&y) {
+                let gx = (luma(pixel(x + 1, y)) - luma(pixel(x - 1, y))) / 2.0;
+                let gy = (luma(pixel(x, y + 1)) - luma(pixel(x, y - 1))) / 2.0;
+                d += config.edge_weight * gx.hypot(gy);
+            }
+            metric::fixed(d * (mask.get_pixel(x, y)[0] as f32 / 255.0), scale) as i64
+        })
+        .sum()
+}
+
+fn luma(c: [f32; 3]) -> f32 {
+    0.2126 * c[0] + 0.7152 * c[1] + 0.0722 * c[2]
+}
+
+fn inside(uv: [f32; 2]) -> bool {
+    uv[0] > 0.0 && uv[0] < 1.0 && uv[1] > 0.0 && uv[1] < 1.0
+}
+
+/// Index of the texel a nearest, repeating sampler would read
+fn texel(uv: [f32; 2], width: u32, height: u32) -> usize {
+    let x = ((uv[0] * width as f32).floor() as i64).rem_euclid(width as i64);
+    let y = ((uv[1] * height as f32).floor() as i64).rem_euclid(height as i64);
+    y as usize * width as usize + x as usize
+}
+
+/// Where a shape's sprites are on the sheet, the same for all of its vertices
+#[derive(Clone, Copy)]
+struct Sprite {
+    page: i32,
+    detail_page: i32,
+    detail_rect: [f32; 4],
+    tex_rect: [f32; 4],
+}
+
+impl Sprite {
+    fn of(vertex: &Vertex) -> Sprite {
+        Sprite {
+            page: vertex.page,
+            detail_page: vertex.detail_page,
+            detail_rect: vertex.detail_rect,
+            tex_rect: vertex.tex_rect,
+        }
+    }
+}
+
+/// The interpolated coordinates of a pixel
+#[derive(Clone, Copy)]
+struct Coords {
+    tex: [f32; 2],
+    detail: [f32; 2],
+    target: [f32; 2],
+}
+
+impl Coords {
+    /// The coordinates `o` pixels away, given how they change one pixel right and down
+    fn offset(self, [dx, dy]: [Coords; 2], o: [f32; 2]) -> Coords {
+        let step = |c: [f32; 2], dx: [f32; 2], dy: [f32; 2]| {
+            [0, 1].map(|i| c[i] + dx[i] * o[0] + dy[i] * o[1])
+        };
+        Coords {
+            tex: step(self.tex, dx.tex, dy.tex),
+            detail: step(self.detail, dx.detail, dy.detail),
+            target: step(self.target, dx.target, dy.target),
+        }
+    }
+}
+
+/// Calls `f` with the pixel index and the interpolated coordinates of every pixel center
+/// the triangles cover, and how they change one pixel right and down like `dpdx` and `dpdy`
+/// do. Vertex positions are in target pixels
+fn rasterize<F>(verts: &[Vertex], viewport: &Size, target_size: &Size, mut f: F)
+where
+    F: FnMut(usize, Coords, [Coords; 2]),
+{
+    let sx = viewport.width as f32 / target_size.width as f32;
+    let sy = viewport.height as f32 / target_size.height as f32;
+
+    for tri in verts.chunks_exact(3) {
+        let mut tri = [tri[0], tri[1], tri[2]];
+        let pos = |v: &Vertex| [v.position[0] * sx, v.position[1] * sy];
+
+        let mut area = edge(pos(&tri[0]), pos(&tri[1]), pos(&tri[2]));
+        if area == 0.0 {
+            continue;
+        }
+        // wind every triangle the same way so shared edges get the same tie break
+        if area < 0.0 {
+            tri.swap(1, 2);
+            area = -area;
+        }
+        let p = tri.map(|v| pos(&v));
+        let coords = |b: [f32; 3]| {
+            let lerp =
+                |a: [[f32; 2]; 3]| [0, 1].map(|i| a[0][i] * b[0] + a[1][i] * b[1] + a[2][i] * b[2]);
+            Coords {
+                tex: lerp(tri.map(|v| v.tex_coords)),
+                detail: lerp(tri.map(|v| v.detail_coords)),
+                target: lerp(tri.map(|v| v.target_coords)),
+            }
+        };
+        // the barycentric weights are linear, these are their slopes
+        let slope = |axis: usize| {
+            [0, 1, 2].map(|i| {
+                let (a, b) = (p[(i + 1) % 3], p[(i + 2) % 3]);
+                match axis {
+                    0 => (a[1] - b[1]) / area,
+                    _ => (b[0] - a[0]) / area,
+                }
+            })
+        };
+        let steps = [coords(slope(0)), coords(slope(1))];
+
+        let min_x = p
+            .iter()
+            .map(|p| p[0])
+            .fold(f32::MAX, f32::min)
+            .floor()
+            .max(0.0) as u32;
+        let min_y = p
+            .iter()
+            .map(|p| p[1])
+            .fold(f32::MAX, f32::min)
+            .floor()
+            .max(0.0) as u32;
+        let max_x =
+            (p.iter().map(|p| p[0]).fold(f32::MIN, f32::max).ceil() as u32).min(viewport.width);
+        let max_y =
+            (p.iter().map(|p| p[1]).fold(f32::MIN, f32::max).ceil() as u32).min(viewport.height);
+
+        for y in min_y..max_y {
+            for x in min_x..max_x {
+                let c = [x as f32 + 0.5, y as f32 + 0.5];
+                let w = [
+                    edge(p[1], p[2], c),
+                    edge(p[2], p[0], c),
+                    edge(p[0], p[1], c),
+                ];
+                let covered = w.iter().enumerate().all(|(i, &w)| {
+                    // pixels exactly on an edge belong to only one of the triangles sharing it
+                    let (a, b) = (p[(i + 1) % 3], p[(i + 2) % 3]);
+                    let (dx, dy) = (b[0] - a[0], b[1] - a[1]);
+                    w > 0.0 || (w == 0.0 && (dy > 0.0 || (dy == 0.0 && dx > 0.0)))
+                });
+                if !covered {
+                    continue;
+                }
+
+                let b = w.map(|w| w / area);
+                f((y * viewport.width + x) as usize, coords(b), steps);
+            }
+        }
+    }
+}
+
+fn edge(a: [f32; 2], b: [f32; 2], c: [f32; 2]) -> f32 {
+    (b[0] - a[0]) * (c[1] - a[1]) - (b[1] - a[1]) * (c[0] - a[0])
+}
@@ -0,0 +1,113 @@
+use std::io::{Read, Write};
+
+use anyhow::Result;
+use base64::alphabet::URL_SAFE;
+use base64::engine::general_purpose::{GeneralPurpose, GeneralPurposeConfig};
+use base64::engine::DecodePaddingMode;
+use base64::Engine;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::plist::{self, Dict, Value};
+
+/// GD pads its base64 but doesn't always keep the padding intact
+pub const BASE64: GeneralPurpose = GeneralPurpose::new(
+    &URL_SAFE,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// A level as saved by the usual level sharing tools, importable through any of them
+pub struct GmdLevel {
+    pub name: String,
+    pub description: String,
+    pub creator: String,
+    /// Official song, 0 is Stereo Madness
+    pub song: u32,
+    pub custom_song: Option<u32>,
+    pub level_string: String,
+}
+
+impl GmdLevel {
+    /// The level's entries, the same ones a level has inside a save file
+    pub fn to_dict(&self) -> Result<Dict> {
+        let mut dict = Dict::default();
+        let int = |i: u32| Value::Integer(i.to_string());
+
+        dict.set("kCEK", int(4));
+        dict.set("k2", Value::String(self.name.clone()));
+        if !self.description.is_empty() {
+            dict.set("k3", Value::String(BASE64.encode(&self.description)));
+        }
+        dict.set(
+            "k4",
+            Value::String(encode_level_data(&self.level_string)?),
+        );
+        if !self.creator.is_empty() {
+            dict.set("k5", Value::String(self.creator.clone()));
+        }
+        dict.set("k8", int(self.song));
+        if let Some(id) = self.custom_song {
+            dict.set("k45", int(id));
+        }
+        dict.set("k13", Value::True);
+        dict.set("k21", int(2));
+        dict.set("k50", int(35));
+        Ok(dict)
+    }
+
+    pub fn to_xml(&self) -> Result<String> {
+        Ok(plist::to_xml(&self.to_dict()?))
+    }
+}
+
+/// Level strings are stored gzipped and url safe base64 encoded
+pub fn encode_level_data(level_string: &str) -> Result<String> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(level_string.as_bytes())?;
+    Ok(BASE64.encode(encoder.finish()?))
+}
+
+pub fn decode_level_data(data: &str) -> Result<String> {
+    let mut level_string = String::new();
+    GzDecoder::new(&BASE64.decode(data.trim_end_matches('\0'))?[..])
+        .read_to_string(&mut level_string)?;
+    Ok(level_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn level_data_round_trip() {
+        let level_string = "kS38,1_40_2_125_3_255_6_1000;1,1,2,15,3,-45.5;";
+        let data = encode_level_data(level_string).unwrap();
+        assert!(!data.contains(['+', '/']));
+        assert_eq!(decode_level_data(&data).unwrap(), level_string);
+        assert!(decode_level_data("not base64!").is_err());
+    }
+
+    #[test]
+    fn xml_parses_back() {
+        let gmd = GmdLevel {
+            name: "seal & friends".into(),
+            description: "made from a picture".into(),
+            creator: String::new(),
+            song: 5,
+            custom_song: Some(546_561),
+            level_string: "1,211,2,30,3,60;".into(),
+        };
+        let dict = plist::parse(&gmd.to_xml().unwrap()).unwrap();
+        assert_eq!(dict.get_str("k2"), Some("seal & friends"));
+        let level_string = decode_level_data(dict.get_str("k4").unwrap()).unwrap();
+        assert_eq!(level_string, gmd.level_string);
+        assert_eq!(dict.get("k8"), Some(&Value::Integer("5".into())));
+        assert_eq!(dict.get("k45"), Some(&Value::Integer("546561".into())));
+        let description = BASE64.decode(dict.get_str("k3").unwrap()).unwrap();
+        assert_eq!(description, b"made from a picture");
+        // an empty creator is left out
+        assert_eq!(dict.get("k5"), None);
+        assert_eq!(dict, gmd.to_dict().unwrap());
+    }
+}
@@ -0,0 +1,385 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+/// Object property keys used by the generator
+pub mod prop {
+    pub const ID: u16 = 1;
+    pub const X: u16 = 2;
+    pub const Y: u16 = 3;
+    pub const FLIP_X: u16 = 4;
+    pub const FLIP_Y: u16 = 5;
+    pub const ROTATION: u16 = 6;
+    pub const RED: u16 = 7;
+    pub const GREEN: u16 = 8;
+    pub const BLUE: u16 = 9;
+    pub const DURATION: u16 = 10;
+    pub const EDITOR_LAYER: u16 = 20;
+    pub const MAIN_COLOR: u16 = 21;
+    pub const DETAIL_COLOR: u16 = 22;
+    pub const TARGET_COLOR: u16 = 23;
+    pub const Z_LAYER: u16 = 24;
+    pub const Z_ORDER: u16 = 25;
+    pub const SCALE: u16 = 32;
+    pub const OPACITY: u16 = 35;
+    pub const TOUCH_TRIGGERED: u16 = 36;
+    pub const MAIN_HSV_ENABLED: u16 = 41;
+    pub const DETAIL_HSV_ENABLED: u16 = 42;
+    pub const MAIN_HSV: u16 = 43;
+    pub const DETAIL_HSV: u16 = 44;
+    pub const TARGET_GROUP: u16 = 51;
+    pub const GROUPS: u16 = 57;
+    pub const CENTER_GROUP: u16 = 71;
+    pub const SCALE_X: u16 = 128;
+    pub const SCALE_Y: u16 = 129;
+}
+
+pub const COLOR_TRIGGER: u16 = 899;
+
+/// Highest color channel or group ID the editor lets you pick
+pub const MAX_ID: u16 = 999;
+
+/// A level string, the header section followed by `;` separated objects
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Level {
+    pub header: Vec<(String, String)>,
+    pub objects: Vec<Object>,
+}
+
+/// A single object, its `key,value` pairs are kept in order and unparsed so they
+/// serialize back exactly as they were read
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Object {
+    pub properties: Vec<(u16, String)>,
+}
+
+/// An HSV adjustment like `210a0.2a0.8a0a0`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hsv {
+    pub h: f32,
+    pub s: f32,
+    pub v: f32,
+    pub s_additive: bool,
+    pub v_additive: bool,
+}
+
+impl Level {
+    pub fn push(&mut self, object: Object) {
+        self.objects.push(object);
+    }
+
+    /// Channels objects are colored with, color triggers change or the header sets up
+    pub fn used_color_channels(&self) -> BTreeSet<u16> {
+        let mut used = BTreeSet::new();
+        for object in &self.objects {
+            used.extend(object.get::<u16>(prop::MAIN_COLOR));
+            used.extend(object.get::<u16>(prop::DETAIL_COLOR));
+            if object.id() == Some(COLOR_TRIGGER) {
+                used.extend(object.get::<u16>(prop::TARGET_COLOR));
+            }
+        }
+        // kS38 is a `|` separated list of `key_value_...` colors, key 6 is the channel
+        let colors = self.header.iter().find(|(k, _)| k == "kS38");
+        for color in colors.map(|(_, v)| v.split('|')).into_iter().flatten() {
+            let values = color.split('_').collect::<Vec<_>>();
+            used.extend(
+                values
+                    .chunks(2)
+                    .filter(|c| c.len() == 2 && c[0] == "6")
+                    .filter_map(|c| c[1].parse::<u16>().ok()),
+            );
+        }
+        used
+    }
+
+    /// Groups objects are in or triggers target
+    pub fn used_groups(&self) -> BTreeSet<u16> {
+        let mut used = BTreeSet::new();
+        for object in &self.objects {
+            if let Some(groups) = object.get_raw(prop::GROUPS) {
+                used.extend(groups.split('.').filter_map(|g| g.parse::<u16>().ok()));
+            }
+            used.extend(object.get::<u16>(prop::TARGET_GROUP));
+            used.extend(object.get::<u16>(prop::CENTER_GROUP));
+        }
+        used
+    }
+
+    pub fn free_color_channel(&self) -> Option<u16> {
+        first_free(&self.used_color_channels())
+    }
+
+    pub fn free_group(&self) -> Option<u16> {
+        first_free(&self.used_groups())
+    }
+}
+
+fn first_free(used: &BTreeSet<u16>) -> Option<u16> {
+    (1..=MAX_ID).find(|id| !used.contains(id))
+}
+
+impl Object {
+    pub fn new() -> Object {
+        Object::default()
+    }
+
+    /// Builder style `set`
+    pub fn with(mut self, key: u16, value: impl fmt::Display) -> Object {
+        self.set(key, value);
+        self
+    }
+
+    /// Replaces the value of `key`, or appends it if the object doesn't have it yet
+    pub fn set(&mut self, key: u16, value: impl fmt::Display) {
+        let value = value.to_string();
+        match self.properties.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, v)) => *v = value,
+            None => self.properties.push((key, value)),
+        }
+    }
+
+    pub fn get_raw(&self, key: u16) -> Option<&str> {
+        self.properties
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// `None` if the property is missing or doesn't parse as `T`
+    pub fn get<T: FromStr>(&self, key: u16) -> Option<T> {
+        self.get_raw(key)?.parse().ok()
+    }
+
+    pub fn id(&self) -> Option<u16> {
+        self.get(prop::ID)
+    }
+
+    /// A touch triggered color trigger that sets `channel` instantly, `color` is 0-255
+    pub fn color_trigger(x: f32, y: f32, channel: u16, color: [f32; 3], opacity: f32) -> Object {
+        Object::new()
+            .with(prop::ID, COLOR_TRIGGER)
+            .with(prop::X, x)
+            .with(prop::Y, y)
+            .with(prop::TOUCH_TRIGGERED, 1)
+            .with(prop::RED, color[0])
+            .with(prop::GREEN, color[1])
+            .with(prop::BLUE, color[2])
+            .with(prop::DURATION, 0)
+            .with(prop::OPACITY, opacity)
+            .with(prop::TARGET_COLOR, channel)
+    }
+}
+
+impl FromStr for Level {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut sections = s.split(';');
+        let header = sections.next().unwrap_or_default();
+        let header = pairs(header)
+            .context("invalid level header")?
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+
+        let objects = sections
+            .filter(|s| !s.is_empty())
+            .enumerate()
+            .map(|(i, s)| s.parse().with_context(|| format!("invalid object {i}")))
+            .collect::<Result<_>>()?;
+
+        Ok(Level { header, objects })
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (k, v)) in self.header.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{k},{v}")?;
+        }
+        f.write_str(";")?;
+        for object in &self.objects {
+            write!(f, "{object};")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Object {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let properties = pairs(s)?
+            .into_iter()
+            .map(|(k, v)| {
+                let k = k
+                    .parse()
+                    .map_err(|_| anyhow!("invalid property key {k:?}"))?;
+                Ok((k, v.to_string()))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Object { properties })
+    }
+}
+
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (k, v)) in self.properties.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{k},{v}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Hsv {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts = s.split('a').collect::<Vec<_>>();
+        if parts.len() != 5 {
+            bail!("expected 5 `a` separated values in hsv {s:?}");
+        }
+        let float = |v: &str| {
+            v.parse::<f32>()
+                .with_context(|| format!("invalid value {v:?} in hsv {s:?}"))
+        };
+        let flag = |v: &str| match v {
+            "0" => Ok(false),
+            "1" => Ok(true),
+            _ => Err(anyhow!("invalid flag {v:?} in hsv {s:?}")),
+        };
+        Ok(Hsv {
+            h: float(parts[0])?,
+            s: float(parts[1])?,
+            v: float(parts[2])?,
+            s_additive: flag(parts[3])?,
+            v_additive: flag(parts[4])?,
+        })
+    }
+}
+
+impl fmt::Display for Hsv {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}a{}a{}a{}a{}",
+            self.h, self.s, self.v, self.s_additive as u8, self.v_additive as u8
+        )
+    }
+}
+
+fn pairs(s: &str) -> Result<Vec<(&str, &str)>> {
+    if s.is_empty() {
+        return Ok(Vec::new());
+    }
+    let values = s.split(',').collect::<Vec<_>>();
+    if values.len() % 2 != 0 {
+        bail!("odd number of values in {s:?}");
+    }
+    Ok(values.chunks(2).map(|c| (c[0], c[1])).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEVEL: &str = "kS38,1_255_2_0_3_0_6_1000|1_0_2_0_3_0_6_1001,kA13,0;\
+        1,1764,2,15,3,45.5,6,90,43,210a0.2a0.8a0a0;1,899,2,-29,3,975,23,5,57,1.2;";
+
+    #[test]
+    fn level_round_trip() {
+        let level = LEVEL.parse::<Level>().unwrap();
+        assert_eq!(level.header.len(), 2);
+        assert_eq!(level.objects.len(), 2);
+        assert_eq!(level.objects[0].get_raw(prop::X), Some("15"));
+        assert_eq!(level.to_string(), LEVEL);
+    }
+
+    #[test]
+    fn generated_level_round_trip() {
+        let generated = include_str!("../levelstring.txt");
+        let level = generated.parse::<Level>().unwrap();
+        assert!(level.header.is_empty());
+        assert!(level.objects.len() > 1000);
+        assert_eq!(level.to_string(), generated);
+    }
+
+    #[test]
+    fn empty_sections_are_dropped() {
+        let level = "kA13,0;;1,1,2,15,3,15;;;1,2,2,45,3,15;"
+            .parse::<Level>()
+            .unwrap();
+        assert_eq!(level.objects.len(), 2);
+        assert_eq!(level.to_string(), "kA13,0;1,1,2,15,3,15;1,2,2,45,3,15;");
+    }
+
+    #[test]
+    fn empty_level() {
+        let level = "".parse::<Level>().unwrap();
+        assert_eq!(level, Level::default());
+        assert_eq!(level.to_string(), ";");
+    }
+
+    #[test]
+    fn invalid_levels() {
+        assert!("kA13;".parse::<Level>().is_err());
+        assert!("kA13,0;1,1,2".parse::<Level>().is_err());
+        assert!("kA13,0;x,1".parse::<Level>().is_err());
+    }
+
+    #[test]
+    fn object_keeps_property_order() {
+        let mut object = "3,15,1,1,2,7.25".parse::<Object>().unwrap();
+        object.set(prop::ID, 211);
+        object.set(prop::ROTATION, 45);
+        assert_eq!(object.id(), Some(211));
+        assert_eq!(object.get::<f32>(prop::X), Some(7.25));
+        assert_eq!(object.get::<u16>(prop::Y), Some(15));
+        assert_eq!(object.to_string(), "3,15,1,211,2,7.25,6,45");
+    }
+
+    #[test]
+    fn hsv_round_trip() {
+        for s in ["210a0.2a0.8a0a0", "-35a1a-0.5a1a1", "0a1a1a0a0"] {
+            assert_eq!(s.parse::<Hsv>().unwrap().to_string(), s);
+        }
+        let hsv = "-35a1a-0.5a0a1".parse::<Hsv>().unwrap();
+        assert_eq!(
+            hsv,
+            Hsv {
+                h: -35.0,
+                s: 1.0,
+                v: -0.5,
+                s_additive: false,
+                v_additive: true,
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_hsv() {
+        assert!("210a0.2a0.8a0".parse::<Hsv>().is_err());
+        assert!("210a0.2a0.8a0a2".parse::<Hsv>().is_err());
+        assert!("xa0.2a0.8a0a0".parse::<Hsv>().is_err());
+    }
+
+    #[test]
+    fn used_ids() {
+        let level = LEVEL.parse::<Level>().unwrap();
+        assert_eq!(
+            level.used_color_channels().into_iter().collect::<Vec<_>>(),
+            [5, 1000, 1001]
+        );
+        assert_eq!(level.free_color_channel(), Some(1));
+        assert_eq!(level.used_groups().into_iter().collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(level.free_group(), Some(3));
+    }
+}
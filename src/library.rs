@@ -0,0 +1,353 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::level::prop;
+use crate::placement::Placement;
+
+/// The objects the optimizer may place, read from a JSON manifest like `objects.json`
+///
+/// Only the enabled and selected objects are kept, shapes refer to them by their index
+/// in `objects`
+pub struct Library {
+    pub objects: Vec<ObjectDef>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ObjectDef {
+    pub id: u16,
+    /// Relative to the manifest, `objects/<id>/main.png` when left out
+    #[serde(default)]
+    pub main: Option<PathBuf>,
+    /// `objects/<id>/detail.png` when left out and that isn't a placeholder
+    #[serde(default)]
+    pub detail: Option<PathBuf>,
+    /// Width and height in GD units at scale 1, the sprite's size when left out
+    #[serde(default)]
+    pub size: Option<[f32; 2]>,
+    #[serde(default = "yes")]
+    pub rotatable: bool,
+    #[serde(default = "yes")]
+    pub scalable: bool,
+    /// The channel that colors the main sprite, the detail sprite gets the other one
+    #[serde(default)]
+    pub color: ColorChannel,
+    /// `detail` is given to the objects with a detail sprite on load
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default = "yes")]
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorChannel {
+    #[default]
+    Main,
+    Detail,
+}
+
+/// An object ID, an ID range like `1000-1100`, or a tag
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Selector {
+    Ids(u16, u16),
+    Tag(String),
+}
+
+/// Which of the enabled objects a run uses, everything when nothing is included
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Selection {
+    /// Name of a preset from the manifest, added to the lists below
+    pub preset: Option<String>,
+    pub include: Vec<Selector>,
+    pub exclude: Vec<Selector>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Preset {
+    #[serde(default)]
+    include: Vec<Selector>,
+    #[serde(default)]
+    exclude: Vec<Selector>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    /// Named selections
+    #[serde(default)]
+    presets: BTreeMap<String, Preset>,
+    objects: Vec<ObjectDef>,
+}
+
+fn yes() -> bool {
+    true
+}
+
+/// Objects without a detail part still ship a tiny empty `detail.png`, and objects the
+/// sprites couldn't be extracted for an empty `main.png`
+fn is_placeholder(path: &Path) -> bool {
+    image::image_dimensions(path).is_ok_and(|(w, h)| w <= 2 && h <= 2)
+}
+
+impl std::str::FromStr for Selector {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let id = |s: &str| {
+            s.trim()
+                .parse::<u16>()
+                .map_err(|_| anyhow!("invalid object id {s:?}"))
+        };
+        if s.is_empty() {
+            bail!("empty object selector");
+        }
+        if !s.starts_with(|c: char| c.is_ascii_digit()) {
+            return Ok(Selector::Tag(s.to_string()));
+        }
+        let (start, end) = match s.split_once('-') {
+            Some((start, end)) => (id(start)?, id(end)?),
+            None => (id(s)?, id(s)?),
+        };
+        if start > end {
+            bail!("object id range {s:?} is backwards");
+        }
+        Ok(Selector::Ids(start, end))
+    }
+}
+
+impl std::fmt::Display for Selector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Selector::Ids(start, end) if start == end => write!(f, "{start}"),
+            Selector::Ids(start, end) => write!(f, "{start}-{end}"),
+            Selector::Tag(tag) => f.write_str(tag),
+        }
+    }
+}
+
+impl TryFrom<String> for Selector {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<Selector> for String {
+    fn from(selector: Selector) -> String {
+        selector.to_string()
+    }
+}
+
+impl Selector {
+    pub fn matches(&self, object: &ObjectDef) -> bool {
+        match self {
+            Selector::Ids(start, end) => (*start..=*end).contains(&object.id),
+            Selector::Tag(tag) => object.tags.contains(tag),
+        }
+    }
+}
+
+impl ColorChannel {
+    pub fn other(self) -> ColorChannel {
+        match self {
+            ColorChannel::Main => ColorChannel::Detail,
+            ColorChannel::Detail => ColorChannel::Main,
+        }
+    }
+
+    /// The properties holding the channel, whether its HSV is enabled, and the HSV
+    pub fn props(self) -> (u16, u16, u16) {
+        match self {
+            ColorChannel::Main => (prop::MAIN_COLOR, prop::MAIN_HSV_ENABLED, prop::MAIN_HSV),
+            ColorChannel::Detail => (
+                prop::DETAIL_COLOR,
+                prop::DETAIL_HSV_ENABLED,
+                prop::DETAIL_HSV,
+            ),
+        }
+    }
+}
+
+impl Library {
+    /// Loads the manifest, resolving the sprite paths and checking they exist, and keeps
+    /// the selected objects
+    pub fn load<P: AsRef<Path>>(path: P, selection: &Selection) -> Result<Library> {
+        let path = path.as_ref();
+        let data =
+            fs::read_to_string(path).with_context(|| format!("couldn't read {}", path.display()))?;
+        let manifest: Manifest = serde_json::from_str(&data)
+            .with_context(|| format!("invalid object manifest {}", path.display()))?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+
+        let mut ids = HashSet::new();
+        let mut objects = Vec::new();
+        for mut object in manifest.objects {
+            let id = object.id;
+            if !ids.insert(id) {
+                bail!("object {id} is listed more than once");
+            }
+            if object.size.is_some_and(|[w, h]| w <= 0.0 || h <= 0.0) {
+                bail!("object {id} has a size that isn't positive");
+            }
+            let main = object
+                .main
+                .take()
+                .unwrap_or_else(|| format!("objects/{id}/main.png").into());
+            object.main = Some(dir.join(main));
+            object.detail = match object.detail.take() {
+                Some(detail) => Some(dir.join(detail)),
+                None => Some(dir.join(format!("objects/{id}/detail.png")))
+                    .filter(|detail| detail.is_file() && !is_placeholder(detail)),
+            };
+            for sprite in object.main.iter().chain(&object.detail) {
+                if !sprite.is_file() {
+                    bail!("object {id} has no sprite at {}", sprite.display());
+                }
+            }
+            // derived from the sprites rather than the manifest, so it can't go stale
+            object.tags.retain(|tag| tag != "detail");
+            if object.detail.is_some() {
+                object.tags.push("detail".into());
+            }
+            if object.enabled && is_placeholder(object.main_sprite()) {
+                bail!("object {id} only has a placeholder sprite, it has to be disabled");
+            }
+            if object.enabled {
+                objects.push(object);
+            }
+        }
+
+        let mut selection = selection.clone();
+        if let Some(name) = &selection.preset {
+            let preset = manifest
+                .presets
+                .get(name)
+                .ok_or_else(|| anyhow!("{} has no preset {name:?}", path.display()))?;
+            selection.include.extend(preset.include.iter().cloned());
+            selection.exclude.extend(preset.exclude.iter().cloned());
+        }
+        // a misspelled tag would otherwise silently select nothing
+        for tag in selection.include.iter().chain(&selection.exclude) {
+            if let Selector::Tag(tag) = tag {
+                if !objects.iter().any(|o: &ObjectDef| o.tags.contains(tag)) {
+                    bail!("no enabled object is tagged {tag:?}");
+                }
+            }
+        }
+        objects.retain(|o| {
+            (selection.include.is_empty() || selection.include.iter().any(|s| s.matches(o)))
+                && !selection.exclude.iter().any(|s| s.matches(o))
+        });
+
+        if objects.is_empty() {
+            bail!("no objects are left to place");
+        }
+        Ok(Library { objects })
+    }
+
+    pub fn ids(&self) -> Vec<u16> {
+        self.objects.iter().map(|o| o.id).collect()
+    }
+}
+
+impl ObjectDef {
+    pub fn main_sprite(&self) -> &Path {
+        self.main.as_deref().expect("sprite paths are resolved on load")
+    }
+
+    /// Half the width and height in working pixels at scale 1, `sprite` is the sprite's size
+    pub fn half_extents(&self, sprite: [u32; 2]) -> [f32; 2] {
+        match self.size {
+            Some([w, h]) => [w, h].map(|s| s / Placement::UNITS_PER_PIXEL / 2.0),
+            None => sprite.map(|s| s as f32 / 2.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest() -> Library {
+        Library::load("objects.json", &Selection::default()).unwrap()
+    }
+
+    #[test]
+    fn manifest_loads() {
+        let ids = manifest().ids();
+        assert!(ids.contains(&1100));
+        assert!(!ids.contains(&104));
+    }
+
+    #[test]
+    fn two_color_preset_has_every_detail_object() {
+        let selection = Selection {
+            preset: Some("two-color".into()),
+            ..Selection::default()
+        };
+        let two_color = Library::load("objects.json", &selection).unwrap();
+        assert!(two_color.objects.iter().all(|o| o.detail.is_some()));
+        let all = manifest();
+        let with_detail = all.objects.iter().filter(|o| o.detail.is_some()).count();
+        assert_eq!(two_color.objects.len(), with_detail);
+    }
+
+    fn select(preset: Option<&str>, include: &str, exclude: &str) -> Result<Library> {
+        let list = |s: &str| {
+            s.split(',')
+                .filter(|s| !s.is_empty())
+                .map(str::parse)
+                .collect::<Result<Vec<_>>>()
+        };
+        let selection = Selection {
+            preset: preset.map(Into::into),
+            include: list(include)?,
+            exclude: list(exclude)?,
+        };
+        Library::load("objects.json", &selection)
+    }
+
+    #[test]
+    fn id_range_without_a_tag() {
+        let library = select(None, "1000-1100", "outline").unwrap();
+        let expected = manifest()
+            .objects
+            .into_iter()
+            .filter(|o| (1000..=1100).contains(&o.id) && !o.tags.iter().any(|t| t == "outline"))
+            .map(|o| o.id)
+            .collect::<Vec<_>>();
+        assert!(expected.contains(&1100));
+        assert_eq!(library.ids(), expected);
+    }
+
+    #[test]
+    fn preset_and_include_add_up() {
+        let library = select(Some("circles"), "1099,1101", "").unwrap();
+        let expected = manifest()
+            .objects
+            .into_iter()
+            .filter(|o| o.tags.iter().any(|t| t == "round") || [1099, 1101].contains(&o.id))
+            .map(|o| o.id)
+            .collect::<Vec<_>>();
+        assert!(expected.len() > 2);
+        assert_eq!(library.ids(), expected);
+    }
+
+    #[test]
+    fn unknown_selections_fail() {
+        assert!(select(Some("nope"), "", "").is_err());
+        assert!(select(None, "nope", "").is_err());
+        assert!(select(None, "", "nope").is_err());
+        assert!(select(None, "5000-6000", "").is_err());
+        assert!("1100-1000".parse::<Selector>().is_err());
+        assert_eq!("7".parse::<Selector>().unwrap(), Selector::Ids(7, 7));
+    }
+}
@@ -1,16 +1,66 @@
-use std::collections::HashMap;
-
+use anyhow::Context;
 use image::imageops::FilterType;
 
-use process::TARGET;
-use texture_packer::exporter::ImageExporter;
-use texture_packer::texture::Texture;
+use backend::Backend;
+use checkpoint::Checkpoint;
+use config::{BackendKind, Command, Config, Transparency};
+use cpu::CpuState;
+use library::Library;
+use shape::{to_srgb, Sheet};
 use wgpu::util::DeviceExt;
 
+use palette::MAX_PALETTE;
 use process::TOTAL_SHAPES;
 
 fn main() {
-    pollster::block_on(run())
+    let (config, checkpoint) = match Config::from_args(std::env::args().skip(1)) {
+        Ok(Command::Run(config)) => (*config, None),
+        Ok(Command::Resume(path)) => match Checkpoint::load(&path) {
+            Ok(checkpoint) => {
+                let mut config = checkpoint.config.clone();
+                // keep saving where the run was resumed from
+                config.checkpoint.get_or_insert(path);
+                (config, Some(checkpoint))
+            }
+            Err(e) => {
+                eprintln!("error: {e:#}");
+                std::process::exit(1);
+            }
+        },
+        Ok(Command::ListLevels(path)) => {
+            if let Err(e) = list_levels(&path) {
+                eprintln!("error: {e:#}");
+                std::process::exit(1);
+            }
+            return;
+        }
+        Ok(Command::Help) => {
+            print!("{}", config::USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("error: {e:#}\n\n{}", config::USAGE);
+            std::process::exit(2);
+        }
+    };
+    pollster::block_on(run(config, checkpoint))
+}
+
+fn list_levels(path: &std::path::Path) -> anyhow::Result<()> {
+    let save = save::LocalLevels::load(path)?;
+    for (i, level) in save.levels().into_iter().enumerate() {
+        let name = level.get_str("k2").unwrap_or_default();
+        let objects = level
+            .get_str("k4")
+            .and_then(|data| gmd::decode_level_data(data).ok())
+            .and_then(|data| data.parse::<level::Level>().ok())
+            .map(|level| level.objects.len());
+        match objects {
+            Some(objects) => println!("{i:4}  {name} ({objects} objects)"),
+            None => println!("{i:4}  {name}"),
+        }
+    }
+    Ok(())
 }
 
 pub(crate) fn lin(c: f32) -> f32 {
@@ -21,60 +71,165 @@ pub(crate) fn lin(c: f32) -> f32 {
     }
 }
 
-async fn run() {
+/// The target resized to `width` by `height`, everything after works on these linear floats
+///
+/// It's resized premultiplied so the result blends light and not sRGB values, and
+/// transparent pixels don't bleed into the edges
+fn linear_target(img: &image::DynamicImage, width: u32, height: u32) -> image::Rgba32FImage {
+    let mut linear = img.to_rgba32f();
+    for p in linear.pixels_mut() {
+        for c in 0..3 {
+            p[c] = lin(p[c]) * p[3];
+        }
+    }
+    let mut target = image::imageops::resize(&linear, width, height, FilterType::Triangle);
+    for p in target.pixels_mut().filter(|p| p[3] > 0.0) {
+        for c in 0..3 {
+            p[c] /= p[3];
+        }
+    }
+    target
+}
+
+/// Average color of the opaque parts of the target, the rest shows the background in the
+/// level too, `None` if the target is completely transparent
+///
+/// It's rounded to the color the background trigger will really show
+fn average_color(target: &image::Rgba32FImage) -> Option<[f32; 3]> {
+    let mut sum = [0.0; 3];
+    let mut coverage = 0.0;
+    for p in target.pixels() {
+        for c in 0..3 {
+            sum[c] += p[3] * p[c];
+        }
+        coverage += p[3];
+    }
+    (coverage > 0.0).then(|| color::quantize(sum.map(|c| c / coverage)))
+}
+
+async fn run(config: Config, checkpoint: Option<Checkpoint>) {
     env_logger::init();
-    let width = 360;
-    let img = image::open(TARGET).unwrap();
+    let width = config.width;
+    let img = image::open(&config.target)
+        .with_context(|| format!("couldn't open {}", config.target.display()))
+        .unwrap_or_else(|e| {
+            eprintln!("error: {e:#}");
+            std::process::exit(1);
+        });
+    // the results are only written once the run is done, so find out now if they can't be
+    let outputs = [&config.output, &config.level];
+    for path in outputs.into_iter().chain(&config.gmd) {
+        if let Err(e) = check_writable(path) {
+            eprintln!("error: {e:#}");
+            std::process::exit(1);
+        }
+    }
     let aspect_ratio = img.width() as f32 / img.height() as f32;
-    let height: u32 = (width as f32 * aspect_ratio) as u32;
+    let height = std::cmp::max((width as f32 / aspect_ratio) as u32, 1);
 
-    let mut target = img.resize(width, height, FilterType::Triangle);
-    let target_size = Size {
-        width: target.width(),
-        height: target.height(),
-    };
+    let mut target = linear_target(&img, width, height);
     let output_size = Size {
-        width: 1024,
-        height: (1024.0 / aspect_ratio) as u32,
+        width: config.output_width,
+        height: std::cmp::max((config.output_width as f32 / aspect_ratio) as u32, 1),
     };
-    // get average color of target image
-    let mut r = 0.0;
-    let mut g = 0.0;
-    let mut b = 0.0;
-    use image::GenericImageView;
-    for pixel in target.pixels() {
-        r += lin(pixel.2[0] as f32 / 255.0);
-        g += lin(pixel.2[1] as f32 / 255.0);
-        b += lin(pixel.2[2] as f32 / 255.0);
-    }
-    r /= target_size.width as f32 * target_size.height as f32;
-    g /= target_size.width as f32 * target_size.height as f32;
-    b /= target_size.width as f32 * target_size.height as f32;
-    let avg_color = [r, g, b];
-
-    // convert this image from srgb to linear
-
-    if let Some(buf) = target.as_mut_rgba8() {
-        for p in buf.pixels_mut() {
-            let r = lin(p[0] as f32 / 255.0);
-            let g = lin(p[1] as f32 / 255.0);
-            let b = lin(p[2] as f32 / 255.0);
-            p[0] = (r * 255.0) as u8;
-            p[1] = (g * 255.0) as u8;
-            p[2] = (b * 255.0) as u8;
-        }
-    } else if let Some(buf) = target.as_mut_rgb8() {
-        for p in buf.pixels_mut() {
-            let r = lin(p[0] as f32 / 255.0);
-            let g = lin(p[1] as f32 / 255.0);
-            let b = lin(p[2] as f32 / 255.0);
-            p[0] = (r * 255.0) as u8;
-            p[1] = (g * 255.0) as u8;
-            p[2] = (b * 255.0) as u8;
+    let avg_color = average_color(&target).unwrap_or_else(|| {
+        eprintln!("error: the target is completely transparent");
+        std::process::exit(1);
+    });
+
+    let mask = mask::build(&config, &target, aspect_ratio).unwrap_or_else(|e| {
+        eprintln!("error: {e:#}");
+        std::process::exit(1);
+    });
+
+    if config.transparent == Transparency::Background {
+        for p in target.pixels_mut() {
+            for c in 0..3 {
+                p[c] = p[c] * p[3] + avg_color[c] * (1.0 - p[3]);
+            }
+            p[3] = 1.0;
         }
     }
 
-    // State::new uses async code, so we're going to wait for it to finish
+    let library = Library::load(&config.objects, &config.selection).unwrap_or_else(|e| {
+        eprintln!("error: {e:#}");
+        std::process::exit(1);
+    });
+    let sheet = match &config.sheet_cache {
+        Some(dir) => sheet_cache::load_or_pack(dir, &library),
+        None => Sheet::pack(&library),
+    }
+    .unwrap_or_else(|e| {
+        eprintln!("error: {e:#}");
+        std::process::exit(1);
+    });
+
+    let gpu = match config.backend {
+        BackendKind::Cpu => None,
+        BackendKind::Gpu => Some(request_device().await.unwrap_or_else(|e| {
+            eprintln!("error: {e:#}, run with --backend cpu to use the CPU instead");
+            std::process::exit(1);
+        })),
+        BackendKind::Auto => match request_device().await {
+            Ok(gpu) => Some(gpu),
+            Err(e) => {
+                println!("{e:#}, running on the CPU");
+                None
+            }
+        },
+    };
+
+    let mut backend: Box<dyn Backend> = match gpu {
+        Some((device, queue)) => Box::new(State::new(
+            device,
+            queue,
+            &target,
+            &mask,
+            output_size,
+            sheet,
+            &config,
+        )),
+        None => Box::new(CpuState::new(&target, &mask, output_size, sheet, &config)),
+    };
+
+    backend.clear(avg_color);
+
+    let error = cpu::image_error(&target, &mask, avg_color, &config);
+    let result = pollster::block_on(process::process(
+        backend.as_mut(),
+        &config,
+        avg_color,
+        error,
+        &library,
+        checkpoint,
+    ));
+    if let Err(e) = result {
+        eprintln!("error: {e:#}");
+        std::process::exit(1);
+    }
+
+    if let Err(e) = backend.render(&config.output) {
+        eprintln!("error: {e:#}");
+        std::process::exit(1);
+    }
+}
+
+/// Fails if `path` can't be written, without changing it or leaving a new file behind
+fn check_writable(path: &std::path::Path) -> anyhow::Result<()> {
+    let existed = path.exists();
+    std::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .with_context(|| format!("couldn't write {}", path.display()))?;
+    if !existed {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Fails if there is no adapter to run on or it can't open a device
+async fn request_device() -> anyhow::Result<(wgpu::Device, wgpu::Queue)> {
     // The instance is a handle to our GPU
     // BackendBit::PRIMARY => Vulkan + Metal + DX12 + Browser WebGPU
     let instance = wgpu::Instance::new(wgpu::Backends::all());
@@ -86,8 +241,8 @@ async fn run() {
             force_fallback_adapter: false,
         })
         .await
-        .unwrap();
-    let (device, queue) = adapter
+        .ok_or_else(|| anyhow::anyhow!("no GPU adapter found"))?;
+    let device = adapter
         .request_device(
             &wgpu::DeviceDescriptor {
                 // limits: wgpu::Limits {
@@ -99,399 +254,434 @@ async fn run() {
             None,
         )
         .await
-        .unwrap();
-
-    let texture_desc = wgpu::TextureDescriptor {
-        size: wgpu::Extent3d {
-            width: target_size.width,
-            height: target_size.height,
-            depth_or_array_layers: 1,
-        },
-        mip_level_count: 1,
-        sample_count: 1,
-        dimension: wgpu::TextureDimension::D2,
-        format: wgpu::TextureFormat::Rgba8UnormSrgb,
-        usage: wgpu::TextureUsages::COPY_SRC
-            | wgpu::TextureUsages::COPY_DST
-            | wgpu::TextureUsages::RENDER_ATTACHMENT
-            | wgpu::TextureUsages::TEXTURE_BINDING,
-        label: None,
-    };
-
-    let output_texture = device.create_texture(&wgpu::TextureDescriptor {
-        size: wgpu::Extent3d {
-            width: output_size.width,
-            height: output_size.height,
-            depth_or_array_layers: 1,
-        },
-        ..texture_desc
-    });
-    let output_texture_view = output_texture.create_view(&Default::default());
-
-    let dummy_texture = device.create_texture(&texture_desc);
-    let dummy_texture_view = dummy_texture.create_view(&Default::default());
+        .map_err(|e| anyhow::anyhow!("couldn't open the GPU: {e}"))?;
+    Ok(device)
+}
 
-    let output_texture =
-        texture::Texture::from_texture(&device, output_texture, output_texture_view).unwrap();
+impl State {
+    pub fn new(
+        device: wgpu::Device,
+        queue: wgpu::Queue,
+        target: &image::Rgba32FImage,
+        mask: &image::GrayImage,
+        output_size: Size,
+        sheet: Sheet,
+        config: &Config,
+    ) -> State {
+        let target_size = Size {
+            width: target.width(),
+            height: target.height(),
+        };
+        let opacity = config.opacity;
+        let texture_desc = wgpu::TextureDescriptor {
+            size: wgpu::Extent3d {
+                width: target_size.width,
+                height: target_size.height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: CANVAS_FORMAT,
+            usage: wgpu::TextureUsages::COPY_SRC
+                | wgpu::TextureUsages::COPY_DST
+                | wgpu::TextureUsages::RENDER_ATTACHMENT
+                | wgpu::TextureUsages::TEXTURE_BINDING,
+            label: None,
+        };
+
+        let output_texture = device.create_texture(&wgpu::TextureDescriptor {
+            size: wgpu::Extent3d {
+                width: output_size.width,
+                height: output_size.height,
+                depth_or_array_layers: 1,
+            },
+            ..texture_desc
+        });
+        let output_texture_view = output_texture.create_view(&Default::default());
+
+        let dummy_texture = device.create_texture(&texture_desc);
+        let dummy_texture_view = dummy_texture.create_view(&Default::default());
+
+        let output_texture =
+            texture::Texture::from_texture(&device, output_texture, output_texture_view).unwrap();
+
+        let output_texture_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                        count: None,
+                    },
+                ],
+                label: Some("output_texture_bind_group_layout"),
+            });
 
-    let output_texture_bind_group_layout =
-        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        let output_texture_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &output_texture_bind_group_layout,
             entries: &[
-                wgpu::BindGroupLayoutEntry {
+                wgpu::BindGroupEntry {
                     binding: 0,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Texture {
-                        multisampled: false,
-                        view_dimension: wgpu::TextureViewDimension::D2,
-                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                    },
-                    count: None,
+                    resource: wgpu::BindingResource::TextureView(&output_texture.view),
                 },
-                wgpu::BindGroupLayoutEntry {
+                wgpu::BindGroupEntry {
                     binding: 1,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-                    count: None,
+                    resource: wgpu::BindingResource::Sampler(&output_texture.sampler),
                 },
             ],
-            label: Some("output_texture_bind_group_layout"),
+            label: Some("output_texture_bind_group"),
         });
 
-    let output_texture_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-        layout: &output_texture_bind_group_layout,
-        entries: &[
-            wgpu::BindGroupEntry {
-                binding: 0,
-                resource: wgpu::BindingResource::TextureView(&output_texture.view),
-            },
-            wgpu::BindGroupEntry {
-                binding: 1,
-                resource: wgpu::BindingResource::Sampler(&output_texture.sampler),
-            },
-        ],
-        label: Some("output_texture_bind_group"),
-    });
-
-    let u32_size = std::mem::size_of::<u32>() as u32;
-
-    let output_buffer_size =
-        (u32_size * output_size.width * output_size.height) as wgpu::BufferAddress;
-    let output_buffer_desc = wgpu::BufferDescriptor {
-        size: output_buffer_size,
-        usage: wgpu::BufferUsages::COPY_DST
-        // this tells wpgu that we want to read this buffer from the cpu
-        | wgpu::BufferUsages::MAP_READ,
-        label: None,
-        mapped_at_creation: false,
-    };
-    let output_buffer = device.create_buffer(&output_buffer_desc);
-
-    let packer = shape::pack_textures();
-
-    let exporter = ImageExporter::export(&packer).unwrap();
-
-    //let spritesheet = exporter.as_rgba8().unwrap().clone();
+        let output_buffer_size =
+            (padded_bytes_per_row(output_size.width) * output_size.height) as wgpu::BufferAddress;
+        let output_buffer_desc = wgpu::BufferDescriptor {
+            size: output_buffer_size,
+            usage: wgpu::BufferUsages::COPY_DST
+            // this tells wpgu that we want to read this buffer from the cpu
+            | wgpu::BufferUsages::MAP_READ,
+            label: None,
+            mapped_at_creation: false,
+        };
+        let output_buffer = device.create_buffer(&output_buffer_desc);
+
+        //let spritesheet = exporter.as_rgba8().unwrap().clone();
+
+        let sheet_texture = texture::Texture::from_pages(
+            &device,
+            &queue,
+            &sheet.pages,
+            wgpu::TextureFormat::Rgba8UnormSrgb,
+        )
+        .unwrap();
 
-    let sheet_texture = texture::Texture::from_image(
-        &device,
-        &queue,
-        &exporter,
-        wgpu::TextureFormat::Rgba8UnormSrgb,
-    )
-    .unwrap();
+        let sheet_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: wgpu::TextureViewDimension::D2Array,
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                        count: None,
+                    },
+                ],
+                label: Some("sheet_bind_group_layout"),
+            });
 
-    let sheet_bind_group_layout =
-        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        let sheet_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &sheet_bind_group_layout,
             entries: &[
-                wgpu::BindGroupLayoutEntry {
+                wgpu::BindGroupEntry {
                     binding: 0,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Texture {
-                        multisampled: false,
-                        view_dimension: wgpu::TextureViewDimension::D2,
-                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                    },
-                    count: None,
+                    resource: wgpu::BindingResource::TextureView(&sheet_texture.view),
                 },
-                wgpu::BindGroupLayoutEntry {
+                wgpu::BindGroupEntry {
                     binding: 1,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-                    count: None,
+                    resource: wgpu::BindingResource::Sampler(&sheet_texture.sampler),
                 },
             ],
-            label: Some("sheet_bind_group_layout"),
+            label: Some("sheet_bind_group"),
         });
 
-    let sheet_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-        layout: &sheet_bind_group_layout,
-        entries: &[
-            wgpu::BindGroupEntry {
-                binding: 0,
-                resource: wgpu::BindingResource::TextureView(&sheet_texture.view),
-            },
-            wgpu::BindGroupEntry {
-                binding: 1,
-                resource: wgpu::BindingResource::Sampler(&sheet_texture.sampler),
-            },
-        ],
-        label: Some("sheet_bind_group"),
-    });
+        let target_texture = texture::Texture::from_linear(&device, &queue, target).unwrap();
 
-    let target_texture =
-        texture::Texture::from_image(&device, &queue, &target, wgpu::TextureFormat::Rgba8Unorm)
-            .unwrap();
+        // the mask goes along as a gray texture, only its red channel is read
+        let mask_texture = texture::Texture::from_image(
+            &device,
+            &queue,
+            &image::DynamicImage::ImageLuma8(mask.clone()),
+            wgpu::TextureFormat::Rgba8Unorm,
+        )
+        .unwrap();
 
-    let target_bind_group_layout =
-        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        let target_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::NonFiltering),
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        },
+                        count: None,
+                    },
+                ],
+
+                label: Some("target_bind_group_layout"),
+            });
+
+        let target_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &target_bind_group_layout,
             entries: &[
-                wgpu::BindGroupLayoutEntry {
+                wgpu::BindGroupEntry {
                     binding: 0,
-                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Texture {
-                        multisampled: false,
-                        view_dimension: wgpu::TextureViewDimension::D2,
-                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                    },
-                    count: None,
+                    resource: wgpu::BindingResource::TextureView(&target_texture.view),
                 },
-                wgpu::BindGroupLayoutEntry {
+                wgpu::BindGroupEntry {
                     binding: 1,
-                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-                    count: None,
+                    resource: wgpu::BindingResource::Sampler(&target_texture.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&mask_texture.view),
                 },
             ],
-
-            label: Some("target_bind_group_layout"),
+            label: Some("sheet_bind_group"),
         });
 
-    let target_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-        layout: &target_bind_group_layout,
-        entries: &[
-            wgpu::BindGroupEntry {
-                binding: 0,
-                resource: wgpu::BindingResource::TextureView(&target_texture.view),
-            },
-            wgpu::BindGroupEntry {
-                binding: 1,
-                resource: wgpu::BindingResource::Sampler(&target_texture.sampler),
-            },
-        ],
-        label: Some("sheet_bind_group"),
-    });
-
-    let shader = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
-        label: Some("Shader"),
-        source: wgpu::ShaderSource::Wgsl(include_str!("shader.wgsl").into()),
-    });
+        let shader = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some("Shader"),
+            // the tint buffer is sized on the rust side, so the shader gets the same constant
+            source: wgpu::ShaderSource::Wgsl(
+                format!(
+                    "let total_shapes = {TOTAL_SHAPES};\nlet max_palette = {MAX_PALETTE};\n\
+                     let metric = {}u;\nlet diff_scale = {:?};\n{}",
+                    config.metric.id(),
+                    metric::diff_scale(config, &target_size),
+                    include_str!("shader.wgsl")
+                )
+                .into(),
+            ),
+        });
 
-    let tint_uniform = TintBuffer {
-        tint: [[0, 0, 0]; TOTAL_SHAPES],
-        counts: [0; TOTAL_SHAPES],
-        opacity: OPACITY,
-        diff: [0; TOTAL_SHAPES],
-    };
+        let tint_uniform = TintBuffer {
+            tint: [[0, 0, 0]; TOTAL_SHAPES],
+            counts: [0; TOTAL_SHAPES],
+            detail_tint: [[0, 0, 0]; TOTAL_SHAPES],
+            detail_counts: [0; TOTAL_SHAPES],
+            opacity,
+            edge_weight: config.edge_weight,
+            palette_size: 0,
+            palette: [[0.0; 3]; MAX_PALETTE],
+            diff: [0; TOTAL_SHAPES],
+        };
+
+        let tint_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Tint buffer"),
+            contents: bytemuck::cast_slice(&[tint_uniform]),
+            usage: wgpu::BufferUsages::STORAGE
+                | wgpu::BufferUsages::COPY_DST
+                | wgpu::BufferUsages::MAP_READ,
+        });
 
-    let tint_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-        label: Some("Tint buffer"),
-        contents: bytemuck::cast_slice(&[tint_uniform]),
-        usage: wgpu::BufferUsages::STORAGE
-            | wgpu::BufferUsages::COPY_DST
-            | wgpu::BufferUsages::MAP_READ,
-    });
+        let tint_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }],
+                label: Some("tint_bind_group_layout"),
+            });
 
-    let tint_bind_group_layout =
-        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-            entries: &[wgpu::BindGroupLayoutEntry {
+        let tint_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &tint_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
                 binding: 0,
-                visibility: wgpu::ShaderStages::FRAGMENT,
-                ty: wgpu::BindingType::Buffer {
-                    ty: wgpu::BufferBindingType::Storage { read_only: false },
-                    has_dynamic_offset: false,
-                    min_binding_size: None,
-                },
-                count: None,
+                resource: tint_buffer.as_entire_binding(),
             }],
-            label: Some("tint_bind_group_layout"),
+            label: Some("tint_bind_group"),
         });
 
-    let tint_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-        layout: &tint_bind_group_layout,
-        entries: &[wgpu::BindGroupEntry {
-            binding: 0,
-            resource: tint_buffer.as_entire_binding(),
-        }],
-        label: Some("tint_bind_group"),
-    });
-
-    let render_layout = &wgpu::PipelineLayoutDescriptor {
-        label: None,
-        bind_group_layouts: &[
-            &sheet_bind_group_layout,
-            &target_bind_group_layout,
-            &tint_bind_group_layout,
-            &output_texture_bind_group_layout,
-        ],
-        push_constant_ranges: &[],
-    };
-
-    let pipeline_def = wgpu::RenderPipelineDescriptor {
-        label: None,
-        layout: None,
-        vertex: wgpu::VertexState {
-            module: &shader,
-            entry_point: "vs_main",
-            buffers: &[Vertex::desc()],
-        },
-        fragment: None,
-        primitive: wgpu::PrimitiveState {
-            topology: wgpu::PrimitiveTopology::TriangleList,
-            strip_index_format: None,
-            front_face: wgpu::FrontFace::Ccw,
-            cull_mode: Some(wgpu::Face::Back),
-            // Setting this to anything other than Fill requires Features::NON_FILL_POLYGON_MODE
-            polygon_mode: wgpu::PolygonMode::Fill,
-            // Requires Features::DEPTH_CLIP_CONTROL
-            unclipped_depth: false,
-            // Requires Features::CONSERVATIVE_RASTERIZATION
-            conservative: false,
-        },
-        depth_stencil: None,
-        multisample: wgpu::MultisampleState {
-            count: 1,
-            mask: !0,
-            alpha_to_coverage_enabled: false,
-        },
-        // If the pipeline will be used with a multiview render pass, this
-        // indicates how many array layers the attachments will have.
-        multiview: None,
-    };
-
-    let avg_color_pipeline_layout = device.create_pipeline_layout(render_layout);
-
-    let avg_color_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-        label: Some("Average color calc. Pipeline"),
-        layout: Some(&avg_color_pipeline_layout),
-        fragment: Some(wgpu::FragmentState {
-            module: &shader,
-            entry_point: "fs_find_avg_color",
-            targets: &[wgpu::ColorTargetState {
-                format: wgpu::TextureFormat::Rgba8UnormSrgb,
-                blend: Some(wgpu::BlendState::ALPHA_BLENDING),
-                write_mask: wgpu::ColorWrites::ALL,
-            }],
-        }),
-        ..pipeline_def.clone()
-    });
-
-    let diff_pipeline_layout = device.create_pipeline_layout(render_layout);
+        let render_layout = &wgpu::PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[
+                &sheet_bind_group_layout,
+                &target_bind_group_layout,
+                &tint_bind_group_layout,
+                &output_texture_bind_group_layout,
+            ],
+            push_constant_ranges: &[],
+        };
+
+        let pipeline_def = wgpu::RenderPipelineDescriptor {
+            label: None,
+            layout: None,
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[Vertex::desc()],
+            },
+            fragment: None,
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: Some(wgpu::Face::Back),
+                // Setting this to anything other than Fill requires Features::NON_FILL_POLYGON_MODE
+                polygon_mode: wgpu::PolygonMode::Fill,
+                // Requires Features::DEPTH_CLIP_CONTROL
+                unclipped_depth: false,
+                // Requires Features::CONSERVATIVE_RASTERIZATION
+                conservative: false,
+            },
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState {
+                count: 1,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            // If the pipeline will be used with a multiview render pass, this
+            // indicates how many array layers the attachments will have.
+            multiview: None,
+        };
+
+        let avg_color_pipeline_layout = device.create_pipeline_layout(render_layout);
+
+        let avg_color_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Average color calc. Pipeline"),
+            layout: Some(&avg_color_pipeline_layout),
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_find_avg_color",
+                targets: &[wgpu::ColorTargetState {
+                    format: CANVAS_FORMAT,
+                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrites::ALL,
+                }],
+            }),
+            ..pipeline_def.clone()
+        });
 
-    let diff_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-        label: Some("Diff calc. Pipeline"),
-        layout: Some(&diff_pipeline_layout),
+        let diff_pipeline_layout = device.create_pipeline_layout(render_layout);
 
-        fragment: Some(wgpu::FragmentState {
-            module: &shader,
-            entry_point: "fs_find_diff",
-            targets: &[wgpu::ColorTargetState {
-                format: wgpu::TextureFormat::Rgba8UnormSrgb,
-                blend: Some(wgpu::BlendState::ALPHA_BLENDING),
-                write_mask: wgpu::ColorWrites::ALL,
-            }],
-        }),
-        ..pipeline_def.clone()
-    });
+        let diff_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Diff calc. Pipeline"),
+            layout: Some(&diff_pipeline_layout),
 
-    let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-        label: Some("Render Pipeline"),
-        layout: Some(
-            &device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: None,
-                bind_group_layouts: &[
-                    &sheet_bind_group_layout,
-                    &target_bind_group_layout,
-                    &tint_bind_group_layout,
-                    //&output_texture_bind_group_layout,
-                ],
-                push_constant_ranges: &[],
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_find_diff",
+                targets: &[wgpu::ColorTargetState {
+                    format: CANVAS_FORMAT,
+                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrites::ALL,
+                }],
             }),
-        ),
-
-        fragment: Some(wgpu::FragmentState {
-            module: &shader,
-            entry_point: "fs_main",
-            targets: &[wgpu::ColorTargetState {
-                format: wgpu::TextureFormat::Rgba8UnormSrgb,
-                blend: Some(wgpu::BlendState::ALPHA_BLENDING),
-                write_mask: wgpu::ColorWrites::ALL,
-            }],
-        }),
-        ..pipeline_def.clone()
-    });
-
-    let state = State {
-        device,
-        queue,
-        avg_color_pipeline,
-        render_pipeline,
-        diff_pipeline,
-        sheet_bind_group,
-        target_bind_group,
-
-        sheet_size: [packer.width(), packer.height()],
-        packer: packer.get_frames().clone(),
-
-        // tint_uniform,
-        tint_bind_group,
-        tint_buffer,
-        output_texture,
-        output_texture_bind_group,
-        // temp_texture,
-        // temp_texture_bind_group_layout,
-        dummy_texture_view,
-        output_buffer,
-        target_size,
-        output_size,
-    };
-
-    let mut encoder = state
-        .device
-        .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+            ..pipeline_def.clone()
+        });
 
-    {
-        let _render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-            label: Some("Render Pass"),
-            color_attachments: &[wgpu::RenderPassColorAttachment {
-                view: &state.output_texture.view,
-                resolve_target: None,
-                ops: wgpu::Operations {
-                    load: wgpu::LoadOp::Clear(wgpu::Color {
-                        r: avg_color[0] as f64,
-                        g: avg_color[1] as f64,
-                        b: avg_color[2] as f64,
-                        a: 1.0,
-                    }),
-
-                    store: true,
-                },
-            }],
-            depth_stencil_attachment: None,
+        let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Render Pipeline"),
+            layout: Some(
+                &device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    label: None,
+                    bind_group_layouts: &[
+                        &sheet_bind_group_layout,
+                        &target_bind_group_layout,
+                        &tint_bind_group_layout,
+                        //&output_texture_bind_group_layout,
+                    ],
+                    push_constant_ranges: &[],
+                }),
+            ),
+
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[wgpu::ColorTargetState {
+                    format: CANVAS_FORMAT,
+                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrites::ALL,
+                }],
+            }),
+            ..pipeline_def.clone()
         });
+
+        State {
+            device,
+            queue,
+            avg_color_pipeline,
+            render_pipeline,
+            diff_pipeline,
+            sheet_bind_group,
+            target_bind_group,
+
+            sheet,
+
+            // tint_uniform,
+            tint_bind_group,
+            tint_buffer,
+            output_texture,
+            output_texture_bind_group,
+            // temp_texture,
+            // temp_texture_bind_group_layout,
+            dummy_texture_view,
+            output_buffer,
+            target_size,
+            output_size,
+            opacity,
+            edge_weight: config.edge_weight,
+            palette: Vec::new(),
+        }
     }
+}
 
-    state.queue.submit(Some(encoder.finish()));
+/// What the canvas is drawn into, linear and fine enough that blending many shapes
+/// doesn't band
+const CANVAS_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
 
-    pollster::block_on(process::process(&state, avg_color));
+/// Bytes of one `CANVAS_FORMAT` pixel
+const CANVAS_PIXEL_SIZE: u32 = 4 * std::mem::size_of::<half::f16>() as u32;
 
-    render(&state, "output.png").await;
+/// Rows copied out of a texture have to be aligned to `COPY_BYTES_PER_ROW_ALIGNMENT`
+fn padded_bytes_per_row(width: u32) -> u32 {
+    let unpadded = CANVAS_PIXEL_SIZE * width;
+    let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+    unpadded.div_ceil(align) * align
 }
 
-pub async fn render<P>(state: &State, path: P)
+/// Fails if the image can't be written to `path`
+pub async fn render<P>(state: &State, path: P) -> anyhow::Result<()>
 where
     P: AsRef<std::path::Path>,
 {
-    let u32_size = std::mem::size_of::<u32>() as u32;
+    let path = path.as_ref();
+    let bytes_per_row = padded_bytes_per_row(state.output_size.width);
     let mut encoder = state
         .device
         .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
@@ -501,7 +691,7 @@ where
             buffer: &state.output_buffer,
             layout: wgpu::ImageDataLayout {
                 offset: 0,
-                bytes_per_row: Some((u32_size * state.output_size.width).try_into().unwrap()),
+                bytes_per_row: Some(bytes_per_row.try_into().unwrap()),
                 rows_per_image: Some(state.output_size.height.try_into().unwrap()),
             },
         },
@@ -512,7 +702,7 @@ where
         },
     );
     state.queue.submit(Some(encoder.finish()));
-    {
+    let result = {
         let buffer_slice = state.output_buffer.slice(..);
 
         // NOTE: We have to create the mapping THEN device.poll() before await
@@ -521,7 +711,19 @@ where
         state.device.poll(wgpu::Maintain::Wait);
         mapping.await.unwrap();
 
+        // the canvas is linear, it's converted to 8 bit sRGB the same way the cpu backend does
         let data = buffer_slice.get_mapped_range();
+        let row_size = (CANVAS_PIXEL_SIZE * state.output_size.width) as usize;
+        let data = data
+            .chunks(bytes_per_row as usize)
+            .flat_map(|row| row[..row_size].chunks(CANVAS_PIXEL_SIZE as usize))
+            .flat_map(|p| {
+                let c = |i: usize| f32::from(half::f16::from_le_bytes([p[2 * i], p[2 * i + 1]]));
+                let [r, g, b, a] = [0, 1, 2, 3].map(c);
+                let a = a.clamp(0.0, 1.0);
+                [to_srgb(r), to_srgb(g), to_srgb(b), a].map(|c| (c * 255.0).round() as u8)
+            })
+            .collect::<Vec<u8>>();
 
         use image::{ImageBuffer, Rgba};
         let buffer = ImageBuffer::<Rgba<u8>, _>::from_raw(
@@ -530,9 +732,12 @@ where
             data,
         )
         .unwrap();
-        buffer.save(path).unwrap();
-    }
+        buffer
+            .save(path)
+            .with_context(|| format!("couldn't write {}", path.display()))
+    };
     state.output_buffer.unmap();
+    result
 }
 
 // mod image_diff;
@@ -552,8 +757,7 @@ pub struct State {
     sheet_bind_group: wgpu::BindGroup,
     target_bind_group: wgpu::BindGroup,
     //view: wgpu::TextureView,
-    sheet_size: [u32; 2],
-    packer: HashMap<u16, texture_packer::Frame<u16>>,
+    sheet: Sheet,
 
     // tint_uniform: TintUniform,
     tint_buffer: wgpu::Buffer,
@@ -569,22 +773,44 @@ pub struct State {
     // temp_texture: texture::Texture,
     // temp_texture_bind_group_layout: wgpu::BindGroupLayout,
     output_buffer: wgpu::Buffer,
+    opacity: f32,
+    edge_weight: f32,
+    /// Written into the tint buffer with every `test_diff`
+    palette: Vec<[f32; 3]>,
 }
 
 mod process;
-use process::OPACITY;
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 struct Vertex {
-    position: [i32; 2],
+    /// In target pixels, not rounded so snapped placements render where they're saved
+    position: [f32; 2],
     tex_coords: [f32; 2],
     tint_index: i32,
     target_coords: [f32; 2],
+    /// Position inside the detail sprite, 0 to 1 across it
+    detail_coords: [f32; 2],
+    /// Where the detail sprite is on the sheet, empty without one
+    detail_rect: [f32; 4],
+    /// Sheet pages of the main and detail sprites
+    page: i32,
+    detail_page: i32,
+    /// Where the main sprite is on the sheet
+    tex_rect: [f32; 4],
 }
 
 impl Vertex {
-    const ATTRIBS: [wgpu::VertexAttribute; 4] =
-        wgpu::vertex_attr_array![0 => Sint32x2, 1 => Float32x2, 2 => Sint32, 3 => Float32x2];
+    const ATTRIBS: [wgpu::VertexAttribute; 9] = wgpu::vertex_attr_array![
+        0 => Float32x2,
+        1 => Float32x2,
+        2 => Sint32,
+        3 => Float32x2,
+        4 => Float32x2,
+        5 => Float32x4,
+        6 => Sint32,
+        7 => Sint32,
+        8 => Float32x4
+    ];
 
     fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
         use std::mem;
@@ -611,7 +837,12 @@ impl Vertex {
 struct TintBuffer {
     tint: [[u32; 3]; TOTAL_SHAPES],
     counts: [u32; TOTAL_SHAPES],
+    detail_tint: [[u32; 3]; TOTAL_SHAPES],
+    detail_counts: [u32; TOTAL_SHAPES],
     opacity: f32,
+    edge_weight: f32,
+    palette_size: u32,
+    palette: [[f32; 3]; MAX_PALETTE],
     diff: [i32; TOTAL_SHAPES],
 }
 
@@ -660,3 +891,166 @@ unsafe impl bytemuck::Pod for DiffBuffer {}
 mod texture;
 
 mod shape;
+
+mod config;
+
+mod backend;
+
+mod cpu;
+
+mod level;
+
+mod gmd;
+
+mod plist;
+
+mod save;
+
+mod placement;
+
+mod checkpoint;
+
+mod library;
+
+mod sheet_cache;
+
+mod color;
+
+mod palette;
+
+mod metric;
+
+mod mask;
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use image::{DynamicImage, Rgb, RgbImage, RgbaImage};
+
+    use super::*;
+    use crate::color::CHANNEL_BASE;
+    use crate::level::{prop, Hsv, Level, COLOR_TRIGGER};
+    use crate::library::{Selection, Selector};
+
+    /// Colors whose hue is a whole degree, so an HSV shift of the red channels can hit them
+    const COLORS: [[u8; 3]; 4] = [[200, 120, 40], [10, 20, 30], [40, 200, 120], [128; 3]];
+
+    /// The 0-255 channels as floats, the way `color::trigger_rgb` gives them
+    fn float_rgb(color: [u8; 3]) -> [f32; 3] {
+        color.map(f32::from)
+    }
+
+    /// Runs a flat `color` target through the whole pipeline on the cpu, over `background`
+    /// instead of the target's own color when there is one, and reads back what it wrote
+    fn run_flat(name: &str, color: [u8; 3], background: Option<[f32; 3]>) -> (Level, RgbaImage) {
+        let dir = std::env::temp_dir().join(format!("gdeometrize-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = Config {
+            output: dir.join("output.png"),
+            level: dir.join("levelstring.txt"),
+            // plain white squares, so their tints are the colors they show
+            selection: Selection {
+                include: vec![Selector::Ids(1099, 1101)],
+                ..Selection::default()
+            },
+            sheet_cache: None,
+            iterations: 3,
+            width: 16,
+            output_width: 32,
+            adjustments: 1,
+            seed: 1,
+            ..Config::default()
+        };
+        let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(64, 48, Rgb(color)));
+
+        let target = linear_target(&img, 16, 12);
+        let avg_color = average_color(&target).unwrap();
+        assert_eq!(color::trigger_rgb(avg_color), float_rgb(color));
+        let bg_color = background.unwrap_or(avg_color);
+        let mask = mask::build(&config, &target, 4.0 / 3.0).unwrap();
+        let library = Library::load(&config.objects, &config.selection).unwrap();
+        let sheet = Sheet::pack(&library).unwrap();
+        let output_size = Size {
+            width: 32,
+            height: 24,
+        };
+        let mut backend = CpuState::new(&target, &mask, output_size, sheet, &config);
+        backend.clear(bg_color);
+        let error = cpu::image_error(&target, &mask, bg_color, &config);
+        assert_eq!(error == 0, background.is_none());
+        pollster::block_on(process::process(
+            &mut backend,
+            &config,
+            bg_color,
+            error,
+            &library,
+            None,
+        ))
+        .unwrap();
+        backend.render(&config.output).unwrap();
+
+        let level = fs::read_to_string(&config.level).unwrap();
+        let level = level.parse::<Level>().unwrap();
+        let output = image::open(&config.output).unwrap().to_rgba8();
+        assert_eq!(output.dimensions(), (32, 24));
+        fs::remove_dir_all(dir).unwrap();
+        (level, output)
+    }
+
+    #[test]
+    fn flat_target_keeps_its_color() {
+        let color = COLORS[0];
+        let (level, output) = run_flat("flat", color, None);
+
+        // nothing improves on a canvas that already is the target
+        assert!(level.objects.iter().all(|o| o.id() == Some(COLOR_TRIGGER)));
+        let background = level
+            .objects
+            .iter()
+            .find(|o| o.get::<u16>(prop::TARGET_COLOR) == Some(1000))
+            .unwrap();
+        let rgb = [prop::RED, prop::GREEN, prop::BLUE].map(|p| background.get::<u8>(p));
+        assert_eq!(rgb, color.map(Some));
+
+        let [r, g, b] = color;
+        assert!(output.pixels().all(|p| p.0 == [r, g, b, 255]));
+    }
+
+    #[test]
+    fn placed_objects_show_the_target_color() {
+        let color = COLORS[2];
+        let (level, _) = run_flat("placed", color, Some([0.02, 0.01, 0.05]));
+
+        let objects = level
+            .objects
+            .iter()
+            .filter(|o| o.id() != Some(COLOR_TRIGGER))
+            .collect::<Vec<_>>();
+        assert!(!objects.is_empty());
+        for object in objects {
+            // the squares have no detail sprite, so only the main channel is shifted
+            assert_eq!(object.get::<u8>(prop::MAIN_HSV_ENABLED), Some(1));
+            assert_eq!(object.get_raw(prop::DETAIL_HSV), None);
+            let hsv = object.get::<Hsv>(prop::MAIN_HSV).unwrap();
+            let shown = color::trigger_rgb(color::apply(CHANNEL_BASE, &hsv));
+            for (shown, expected) in shown.into_iter().zip(float_rgb(color)) {
+                let off = (shown - expected).abs();
+                assert!(off <= 2.0, "{hsv} shows {shown} for {expected}");
+            }
+        }
+    }
+
+    #[test]
+    fn hsv_reproduces_flat_colors() {
+        for color in COLORS {
+            let linear = float_rgb(color).map(|c| lin(c / 255.0));
+            let solved = color::solve(CHANNEL_BASE, linear);
+            assert!(!solved.clipped);
+            // as it's written into the level
+            let hsv = solved.hsv.to_string().parse::<Hsv>().unwrap();
+            let shown = color::apply(CHANNEL_BASE, &hsv);
+            assert_eq!(color::trigger_rgb(shown), float_rgb(color), "{hsv}");
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+use image::{imageops::FilterType, GrayImage, Luma, Rgba32FImage};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Config, Transparency};
+use crate::shape::to_srgb;
+
+/// Lowest weight of the automatic mask, so flat regions still count for something
+const AUTO_FLOOR: f32 = 0.25;
+
+/// Where the importance mask comes from
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MaskSource {
+    /// A grayscale image, white where the target matters most
+    Image(PathBuf),
+    /// Weighted by how many edges there are around every pixel of the target
+    Auto,
+}
+
+impl std::str::FromStr for MaskSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "auto" => MaskSource::Auto,
+            _ => MaskSource::Image(s.into()),
+        })
+    }
+}
+
+/// How much every pixel of the resized linear target counts, from 0 to 255
+///
+/// Every pixel counts fully without a mask, except transparent ones that don't stand for
+/// the background. `aspect_ratio` is the one of the original target, the resized one is
+/// rounded to whole pixels
+pub fn build(config: &Config, target: &Rgba32FImage, aspect_ratio: f32) -> Result<GrayImage> {
+    let mut mask = from_source(config.mask.as_ref(), target, aspect_ratio)?;
+    if config.transparent == Transparency::Ignore {
+        for (m, p) in mask.pixels_mut().zip(target.pixels()) {
+            m[0] = (m[0] as f32 * p[3]).round() as u8;
+        }
+    }
+    Ok(mask)
+}
+
+fn from_source(
+    source: Option<&MaskSource>,
+    target: &Rgba32FImage,
+    aspect_ratio: f32,
+) -> Result<GrayImage> {
+    let (width, height) = target.dimensions();
+    match source {
+        None => Ok(GrayImage::from_pixel(width, height, Luma([255]))),
+        Some(MaskSource::Image(path)) => {
+            let mask = image::open(path)
+                .with_context(|| format!("failed to open mask {}", path.display()))?;
+            let mask_ratio = mask.width() as f32 / mask.height() as f32;
+            if (mask_ratio / aspect_ratio - 1.0).abs() > 0.01 {
+                bail!(
+                    "mask {} is {}x{}, which isn't the target's aspect ratio",
+                    path.display(),
+                    mask.width(),
+                    mask.height()
+                );
+            }
+            Ok(mask
+                .resize_exact(width, height, FilterType::Triangle)
+                .to_luma8())
+        }
+        Some(MaskSource::Auto) => Ok(edge_density(target)),
+    }
+}
+
+/// The luminance gradient of the target blurred over a few pixels, so the area around
+/// detailed parts like faces and text gets weighted up and not just their outlines
+///
+/// The gradient is taken of the sRGB values, which are closer to how different they look
+fn edge_density(target: &Rgba32FImage) -> GrayImage {
+    let luma = target
+        .pixels()
+        .map(|p| to_srgb(0.2126 * p[0] + 0.7152 * p[1] + 0.0722 * p[2]))
+        .collect::<Vec<_>>();
+    let (width, height) = target.dimensions();
+    let (w, h) = (width as i64, height as i64);
+    let at = |values: &[f32], x: i64, y: i64| {
+        values[(y.clamp(0, h - 1) * w + x.clamp(0, w - 1)) as usize]
+    };
+
+    let gradient = (0..h)
+        .flat_map(|y| (0..w).map(move |x| (x, y)))
+        .map(|(x, y)| {
+            let gx = at(&luma, x + 1, y) - at(&luma, x - 1, y);
+            let gy = at(&luma, x, y + 1) - at(&luma, x, y - 1);
+            gx.hypot(gy) / 2.0
+        })
+        .collect::<Vec<_>>();
+
+    // a box blur, once along each axis
+    let radius = (w.max(h) / 32).max(1);
+    let blur = |values: &[f32], dx: i64, dy: i64| {
+        (0..h)
+            .flat_map(|y| (0..w).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                let sum = (-radius..=radius)
+                    .map(|i| at(values, x + i * dx, y + i * dy))
+                    .sum::<f32>();
+                sum / (2 * radius + 1) as f32
+            })
+            .collect::<Vec<_>>()
+    };
+    let density = blur(&blur(&gradient, 1, 0), 0, 1);
+
+    let max = density.iter().copied().fold(0.0, f32::max);
+    GrayImage::from_fn(width, height, |x, y| {
+        let d = match max > 0.0 {
+            true => density[(y * width + x) as usize] / max,
+            false => 1.0,
+        };
+        Luma([((AUTO_FLOOR + (1.0 - AUTO_FLOOR) * d) * 255.0).round() as u8])
+    })
+}
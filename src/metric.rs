@@ -0,0 +1,282 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::Size;
+
+/// No metric gets far past 3 between two colors of a canvas, this leaves room for estimated
+/// tints that overshoot
+const MAX_DIFF: f32 = 4.0;
+
+/// The finest scale the differences are summed up at
+const MAX_DIFF_SCALE: f32 = 65536.0;
+
+/// How the difference between two linear colors is measured
+///
+/// Every one is implemented twice, here for the cpu backend and the error estimate, and
+/// as `color_diff` in the shader, which picks one through the `metric` constant
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Metric {
+    /// Mean squared error of the channels
+    Rgb,
+    /// Euclidean distance weighted by how red the colors are
+    #[default]
+    Redmean,
+    /// CIELAB ΔE*76
+    Cie76,
+    /// CIELAB ΔE*00
+    Ciede2000,
+    /// Euclidean distance in OKLab
+    Oklab,
+}
+
+impl std::str::FromStr for Metric {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "rgb" => Ok(Metric::Rgb),
+            "redmean" => Ok(Metric::Redmean),
+            "cie76" => Ok(Metric::Cie76),
+            "ciede2000" => Ok(Metric::Ciede2000),
+            "oklab" => Ok(Metric::Oklab),
+            _ => bail!("expected rgb, redmean, cie76, ciede2000 or oklab"),
+        }
+    }
+}
+
+impl Metric {
+    /// The value of the shader's `metric` constant
+    pub fn id(self) -> u32 {
+        self as u32
+    }
+
+    /// Same as `color_diff` in the shader
+    ///
+    /// The CIELAB differences are divided by 100 so all of them are roughly between 0 and 1
+    pub fn diff(self, p1: [f32; 3], p2: [f32; 3]) -> f32 {
+        match self {
+            Metric::Rgb => squared_distance(p1, p2) / 3.0,
+            Metric::Redmean => {
+                let d = [p1[0] - p2[0], p1[1] - p2[1], p1[2] - p2[2]];
+                let rdash = (p1[0] + p2[0]) / 2.0;
+                ((2.0 + rdash) * d[0] * d[0] + 4.0 * d[1] * d[1] + (3.0 - rdash) * d[2] * d[2])
+                    .sqrt()
+            }
+            Metric::Cie76 => distance(lab(p1), lab(p2)) / 100.0,
+            Metric::Ciede2000 => ciede2000(lab(p1), lab(p2)) / 100.0,
+            Metric::Oklab => distance(oklab(p1), oklab(p2)),
+        }
+    }
+}
+
+/// How much a per pixel difference of 1 adds to the i32 sums of the diff pass, on both
+/// backends
+///
+/// As fine as it can be while a shape covering the whole canvas of `size` stays well clear
+/// of overflowing them, so small differences like squared RGB ones don't round away
+pub fn diff_scale(config: &Config, size: &Size) -> f32 {
+    let pixels = size.width as f32 * size.height as f32;
+    // neither gradient changes by more than √2 in the edge term
+    let max = MAX_DIFF + std::f32::consts::SQRT_2 * config.edge_weight;
+    ((i32::MAX / 2) as f32 / (pixels * max)).min(MAX_DIFF_SCALE)
+}
+
+/// A per pixel difference as it's added to the sums of the diff pass
+pub fn fixed(diff: f32, scale: f32) -> i32 {
+    (diff * scale + 0.5).floor() as i32
+}
+
+/// A sum of differences at `scale` in the units errors are shown in, 255 for a difference
+/// of 1 on every pixel
+pub fn error_units(sum: f64, scale: f32) -> f64 {
+    sum * 255.0 / scale as f64
+}
+
+fn squared_distance(a: [f32; 3], b: [f32; 3]) -> f32 {
+    (0..3).map(|c| (a[c] - b[c]) * (a[c] - b[c])).sum()
+}
+
+fn distance(a: [f32; 3], b: [f32; 3]) -> f32 {
+    squared_distance(a, b).sqrt()
+}
+
+/// CIELAB of a linear sRGB color, relative to D65 white
+fn lab([r, g, b]: [f32; 3]) -> [f32; 3] {
+    let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+    let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+    let f = |t: f32| {
+        if t > 216.0 / 24389.0 {
+            t.cbrt()
+        } else {
+            (24389.0 / 27.0 * t + 16.0) / 116.0
+        }
+    };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+/// OKLab of a linear sRGB color
+fn oklab([r, g, b]: [f32; 3]) -> [f32; 3] {
+    let l = (0.412_221_47 * r + 0.536_332_55 * g + 0.051_445_993 * b).cbrt();
+    let m = (0.211_903_5 * r + 0.680_699_5 * g + 0.107_396_96 * b).cbrt();
+    let s = (0.088_302_46 * r + 0.281_718_85 * g + 0.629_978_7 * b).cbrt();
+    [
+        0.210_454_26 * l + 0.793_617_8 * m - 0.004_072_047 * s,
+        1.977_998_5 * l - 2.428_592_2 * m + 0.450_593_7 * s,
+        0.025_904_037 * l + 0.782_771_77 * m - 0.808_675_77 * s,
+    ]
+}
+
+/// ΔE*00 with all weights at 1, following Sharma, Wu and Dalal
+fn ciede2000([l1, a1, b1]: [f32; 3], [l2, a2, b2]: [f32; 3]) -> f32 {
+    let pow7 = |x: f32| x.powi(7);
+    let c_bar = ((a1 * a1 + b1 * b1).sqrt() + (a2 * a2 + b2 * b2).sqrt()) / 2.0;
+    let g = 0.5 * (1.0 - (pow7(c_bar) / (pow7(c_bar) + pow7(25.0))).sqrt());
+    let (a1, a2) = ((1.0 + g) * a1, (1.0 + g) * a2);
+    let (c1, c2) = ((a1 * a1 + b1 * b1).sqrt(), (a2 * a2 + b2 * b2).sqrt());
+    let hue = |b: f32, a: f32| b.atan2(a).to_degrees().rem_euclid(360.0);
+    let (h1, h2) = (hue(b1, a1), hue(b2, a2));
+    let chromatic = c1 * c2 != 0.0;
+
+    let dh = if !chromatic {
+        0.0
+    } else if (h2 - h1).abs() <= 180.0 {
+        h2 - h1
+    } else if h2 - h1 > 180.0 {
+        h2 - h1 - 360.0
+    } else {
+        h2 - h1 + 360.0
+    };
+    let dl = l2 - l1;
+    let dc = c2 - c1;
+    let dh = 2.0 * (c1 * c2).sqrt() * (dh / 2.0).to_radians().sin();
+
+    let l_bar = (l1 + l2) / 2.0;
+    let c_bar = (c1 + c2) / 2.0;
+    let h_bar = if !chromatic {
+        h1 + h2
+    } else if (h1 - h2).abs() <= 180.0 {
+        (h1 + h2) / 2.0
+    } else if h1 + h2 < 360.0 {
+        (h1 + h2 + 360.0) / 2.0
+    } else {
+        (h1 + h2 - 360.0) / 2.0
+    };
+    let cos = |deg: f32| deg.to_radians().cos();
+    let t =
+        1.0 - 0.17 * cos(h_bar - 30.0) + 0.24 * cos(2.0 * h_bar) + 0.32 * cos(3.0 * h_bar + 6.0)
+            - 0.20 * cos(4.0 * h_bar - 63.0);
+    let d_theta = 30.0 * (-((h_bar - 275.0) / 25.0).powi(2)).exp();
+    let rc = 2.0 * (pow7(c_bar) / (pow7(c_bar) + pow7(25.0))).sqrt();
+    let sl = 1.0 + 0.015 * (l_bar - 50.0).powi(2) / (20.0 + (l_bar - 50.0).powi(2)).sqrt();
+    let sc = 1.0 + 0.045 * c_bar;
+    let sh = 1.0 + 0.015 * c_bar * t;
+    let rt = -(2.0 * d_theta).to_radians().sin() * rc;
+
+    let (l, c, h) = (dl / sl, dc / sc, dh / sh);
+    (l * l + c * c + h * h + rt * c * h).max(0.0).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f32, b: f32, tolerance: f32) {
+        assert!((a - b).abs() < tolerance, "{a} != {b}");
+    }
+
+    #[test]
+    fn ciede2000_matches_published_pairs() {
+        // from Sharma, Wu and Dalal's test data
+        let pairs = [
+            ([50.0, 2.6772, -79.7751], [50.0, 0.0, -82.7485], 2.0425),
+            ([50.0, 3.1571, -77.2803], [50.0, 0.0, -82.7485], 2.8615),
+            ([50.0, 0.0, 0.0], [50.0, -1.0, 2.0], 2.3669),
+            ([50.0, 2.5, 0.0], [73.0, 25.0, -18.0], 27.1492),
+            ([50.0, 2.5, 0.0], [61.0, -5.0, 29.0], 22.8977),
+            (
+                [60.2574, -34.0099, 36.2677],
+                [60.4626, -34.1751, 39.4387],
+                1.2644,
+            ),
+            (
+                [22.7233, 20.0904, -46.694],
+                [23.0331, 14.973, -42.5619],
+                2.0373,
+            ),
+        ];
+        for (lab1, lab2, expected) in pairs {
+            assert_close(ciede2000(lab1, lab2), expected, 1e-3);
+            assert_close(ciede2000(lab2, lab1), expected, 1e-3);
+        }
+    }
+
+    #[test]
+    fn oklab_matches_published_values() {
+        // Björn Ottosson's values for the sRGB primaries
+        let red = [0.627_955_4, 0.224_863_06, 0.125_846_3];
+        let blue = [0.452_013_7, -0.032_457_02, -0.311_528_1];
+        for (c, expected) in oklab([1.0, 0.0, 0.0]).into_iter().zip(red) {
+            assert_close(c, expected, 1e-4);
+        }
+        for (c, expected) in oklab([0.0, 0.0, 1.0]).into_iter().zip(blue) {
+            assert_close(c, expected, 1e-4);
+        }
+        let d = Metric::Oklab.diff([1.0, 0.0, 0.0], [0.0, 0.0, 1.0]);
+        assert_close(d, distance(red, blue), 1e-4);
+        assert_close(Metric::Oklab.diff([1.0; 3], [0.0; 3]), 1.0, 1e-4);
+    }
+
+    #[test]
+    fn black_and_white_are_one_apart() {
+        for metric in [Metric::Cie76, Metric::Ciede2000, Metric::Oklab] {
+            assert_close(metric.diff([1.0; 3], [0.0; 3]), 1.0, 1e-3);
+            assert_eq!(metric.diff([0.3, 0.6, 0.1], [0.3, 0.6, 0.1]), 0.0);
+        }
+        assert_close(Metric::Rgb.diff([1.0, 0.0, 0.0], [0.0; 3]), 1.0 / 3.0, 1e-6);
+    }
+
+    #[test]
+    fn diff_scale_leaves_room_for_a_full_canvas() {
+        let mut config = Config {
+            edge_weight: 2.0,
+            ..Config::default()
+        };
+        let metrics = [
+            Metric::Rgb,
+            Metric::Redmean,
+            Metric::Cie76,
+            Metric::Ciede2000,
+            Metric::Oklab,
+        ];
+        for metric in metrics {
+            config.metric = metric;
+            for (width, height) in [(40, 30), (360, 240), (1024, 1024), (4096, 4096)] {
+                let scale = diff_scale(&config, &Size { width, height });
+                let max = MAX_DIFF + std::f32::consts::SQRT_2 * config.edge_weight;
+                let worst = width as i64 * height as i64 * fixed(max, scale) as i64;
+                assert!(worst < i32::MAX as i64, "{metric:?} at {width}x{height}");
+            }
+        }
+    }
+
+    #[test]
+    fn small_differences_count() {
+        let config = Config {
+            metric: Metric::Rgb,
+            ..Config::default()
+        };
+        let scale = diff_scale(
+            &config,
+            &Size {
+                width: 360,
+                height: 240,
+            },
+        );
+        let d = Metric::Rgb.diff([0.5; 3], [0.52; 3]);
+        assert!(fixed(d, scale) > 0);
+    }
+}
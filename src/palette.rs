@@ -0,0 +1,87 @@
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::color;
+
+/// Most colors a palette can have, the shader is built with the same value
+pub const MAX_PALETTE: usize = 64;
+
+/// Rounds of k-means, palettes are small so they settle long before this
+const ROUNDS: usize = 50;
+
+/// Squared distance in linear RGB, the same one `snap_to_palette` in the shader uses
+fn distance(a: [f32; 3], b: [f32; 3]) -> f32 {
+    (0..3).map(|c| (a[c] - b[c]) * (a[c] - b[c])).sum()
+}
+
+/// Index of the palette color closest to `color`
+pub fn nearest(palette: &[[f32; 3]], color: [f32; 3]) -> usize {
+    let mut best = 0;
+    for (i, p) in palette.iter().enumerate() {
+        if distance(*p, color) < distance(palette[best], color) {
+            best = i;
+        }
+    }
+    best
+}
+
+/// Groups linear tints into at most `size` colors with k-means
+///
+/// The tints are clamped to what a color channel can show first, and the colors are
+/// rounded to the whole sRGB values a color trigger stores, so every one can be set as is
+pub fn cluster(tints: &[[f32; 3]], size: usize, seed: u64) -> Vec<[f32; 3]> {
+    let tints = tints
+        .iter()
+        .map(|t| t.map(|c| c.clamp(0.0, 1.0)))
+        .collect::<Vec<_>>();
+    if tints.is_empty() {
+        return vec![[1.0; 3]];
+    }
+
+    // k-means++, every next center is picked with a chance growing with its distance
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut palette = vec![tints[rng.gen_range(0..tints.len())]];
+    while palette.len() < size {
+        let weights = tints
+            .iter()
+            .map(|t| distance(palette[nearest(&palette, *t)], *t))
+            .collect::<Vec<_>>();
+        let total = weights.iter().sum::<f32>();
+        if total <= 0.0 {
+            // fewer distinct tints than colors
+            break;
+        }
+        let mut pick = rng.gen_range(0.0..total);
+        let next = weights
+            .iter()
+            .position(|w| {
+                pick -= w;
+                pick < 0.0
+            })
+            .unwrap_or(tints.len() - 1);
+        palette.push(tints[next]);
+    }
+
+    for _ in 0..ROUNDS {
+        let mut sums = vec![([0.0; 3], 0usize); palette.len()];
+        for t in &tints {
+            let (sum, count) = &mut sums[nearest(&palette, *t)];
+            for c in 0..3 {
+                sum[c] += t[c];
+            }
+            *count += 1;
+        }
+        let next = palette
+            .iter()
+            .zip(&sums)
+            .map(|(p, (sum, count))| match count {
+                0 => *p,
+                n => sum.map(|s| s / *n as f32),
+            })
+            .collect::<Vec<_>>();
+        if next == palette {
+            break;
+        }
+        palette = next;
+    }
+    palette.into_iter().map(color::quantize).collect()
+}
@@ -0,0 +1,208 @@
+use serde::{Deserialize, Serialize};
+
+/// Maps the optimizer's image space (working pixels, y pointing down) onto the level
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Placement {
+    /// Position of the image's top left corner in GD units
+    pub origin: [f32; 2],
+    /// 1 places one working pixel every half unit
+    pub scale: f32,
+    /// GD's y axis points up, so the image has to be flipped to stay upright,
+    /// without this the art comes out mirrored
+    pub flip_y: bool,
+    /// Clockwise degrees the whole image is turned around `origin`
+    pub rotation: f32,
+    /// Only scale objects evenly and write that as property 32, GD before 2.2 has no
+    /// separate x and y scale
+    pub uniform_scale: bool,
+    pub precision: Precision,
+}
+
+/// Steps the level's transforms are snapped to, the editor rounds them when the level is
+/// loaded, 0 keeps full precision
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Precision {
+    /// GD units
+    pub position: f32,
+    /// Degrees
+    pub rotation: f32,
+    pub scale: f32,
+}
+
+/// An object's transform in the level
+#[derive(Debug, Clone, Copy)]
+pub struct Placed {
+    pub x: f32,
+    pub y: f32,
+    /// Clockwise degrees
+    pub rotation: f32,
+    pub scale_x: f32,
+    pub scale_y: f32,
+    pub flip_y: bool,
+}
+
+impl Default for Placement {
+    fn default() -> Self {
+        Placement {
+            origin: [0.0, 0.0],
+            scale: 1.0,
+            flip_y: true,
+            rotation: 0.0,
+            uniform_scale: false,
+            precision: Precision::default(),
+        }
+    }
+}
+
+impl Default for Precision {
+    fn default() -> Self {
+        Precision {
+            position: 0.01,
+            rotation: 0.01,
+            scale: 0.01,
+        }
+    }
+}
+
+/// Rounds `v` to a multiple of `step`
+fn snap(v: f32, step: f32) -> f32 {
+    if step <= 0.0 {
+        return v;
+    }
+    // dividing by the inverse keeps steps like 0.01 from printing as 87.149994
+    let inv = 1.0 / step;
+    (v * inv).round() / inv
+}
+
+impl Placement {
+    /// GD units per working pixel at scale 1, the sprites are 2 pixels per unit
+    pub const UNITS_PER_PIXEL: f32 = 0.5;
+    pub const UNITS_PER_BLOCK: f32 = 30.0;
+
+    /// The scale that makes an image `width` working pixels wide span `blocks` blocks
+    pub fn scale_for_width(blocks: f32, width: u32) -> f32 {
+        blocks * Self::UNITS_PER_BLOCK / (width as f32 * Self::UNITS_PER_PIXEL)
+    }
+
+    /// `x`, `y` in working pixels and `rot` in radians, like `Shape`
+    pub fn place(&self, x: f32, y: f32, rot: f32, [scale_x, scale_y]: [f32; 2]) -> Placed {
+        let x = x * Self::UNITS_PER_PIXEL * self.scale;
+        let y = y * Self::UNITS_PER_PIXEL * self.scale;
+
+        // clockwise with y pointing down
+        let (sin, cos) = self.rotation.to_radians().sin_cos();
+        let (x, y) = (x * cos - y * sin, x * sin + y * cos);
+        let rotation = rot * 180.0 / std::f32::consts::PI + self.rotation;
+
+        let placed = if self.flip_y {
+            Placed {
+                x: self.origin[0] + x,
+                y: self.origin[1] - y,
+                rotation,
+                scale_x: scale_x * self.scale,
+                scale_y: scale_y * self.scale,
+                flip_y: false,
+            }
+        } else {
+            // mirroring a turned sprite is the same as flipping it and turning it back
+            Placed {
+                x: self.origin[0] + x,
+                y: self.origin[1] + y,
+                rotation: -rotation,
+                scale_x: scale_x * self.scale,
+                scale_y: scale_y * self.scale,
+                flip_y: true,
+            }
+        };
+
+        let Precision {
+            position,
+            rotation,
+            scale,
+        } = self.precision;
+        Placed {
+            x: snap(placed.x, position),
+            y: snap(placed.y, position),
+            rotation: snap(placed.rotation, rotation),
+            // a scale of 0 hides the object
+            scale_x: snap(placed.scale_x, scale).max(scale),
+            scale_y: snap(placed.scale_y, scale).max(scale),
+            ..placed
+        }
+    }
+
+    /// The inverse of `place`, gives `x`, `y`, `rot` and the scale in image space
+    pub fn unplace(&self, placed: &Placed) -> (f32, f32, f32, [f32; 2]) {
+        let x = placed.x - self.origin[0];
+        let (y, rotation) = if self.flip_y {
+            (self.origin[1] - placed.y, placed.rotation)
+        } else {
+            (placed.y - self.origin[1], -placed.rotation)
+        };
+
+        let (sin, cos) = self.rotation.to_radians().sin_cos();
+        let (x, y) = (x * cos + y * sin, y * cos - x * sin);
+        let k = Self::UNITS_PER_PIXEL * self.scale;
+
+        (
+            x / k,
+            y / k,
+            (rotation - self.rotation) * std::f32::consts::PI / 180.0,
+            [placed.scale_x, placed.scale_y].map(|s| s / self.scale),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-3, "{a} != {b}");
+    }
+
+    #[test]
+    fn unplace_inverts_place() {
+        for flip_y in [true, false] {
+            for rotation in [0.0, 30.0, -135.0] {
+                let placement = Placement {
+                    origin: [105.0, -45.0],
+                    scale: 1.5,
+                    flip_y,
+                    rotation,
+                    uniform_scale: false,
+                    precision: Precision {
+                        position: 0.0,
+                        rotation: 0.0,
+                        scale: 0.0,
+                    },
+                };
+                let (x, y, rot, scale) = (37.25, 12.5, 0.8, [2.5, 0.75]);
+                let placed = placement.place(x, y, rot, scale);
+                assert_eq!(placed.flip_y, !flip_y);
+                let (ux, uy, urot, uscale) = placement.unplace(&placed);
+                assert_close(ux, x);
+                assert_close(uy, y);
+                assert_close(urot, rot);
+                assert_close(uscale[0], scale[0]);
+                assert_close(uscale[1], scale[1]);
+            }
+        }
+    }
+
+    #[test]
+    fn snapped_placements_unplace_onto_the_grid() {
+        let placement = Placement {
+            rotation: 30.0,
+            ..Placement::default()
+        };
+        let placed = placement.place(37.3, 12.6, 0.8, [2.5, 0.75]);
+        let (x, y, rot, scale) = placement.unplace(&placed);
+        let again = placement.place(x, y, rot, scale);
+        assert_close(again.x, placed.x);
+        assert_close(again.y, placed.y);
+        assert_close(again.rotation, placed.rotation);
+        assert_eq!(again.scale_x, placed.scale_x);
+        assert_close(placed.x * 100.0, (placed.x * 100.0).round());
+    }
+}
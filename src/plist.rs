@@ -0,0 +1,265 @@
+use anyhow::{anyhow, bail, Context, Result};
+
+/// The plist dialect GD uses for its saves and .gmd files, `<d>` dicts with `<k>` keys
+/// followed by `<s>`, `<i>`, `<r>` or `<t />` values
+///
+/// Numbers are kept as text so untouched entries are written back exactly as they were read
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Dict(Dict),
+    String(String),
+    Integer(String),
+    Real(String),
+    True,
+    False,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Dict(pub Vec<(String, Value)>);
+
+impl Dict {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.0.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut Value> {
+        self.0.iter_mut().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        match self.get(key)? {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Replaces the value of `key`, or appends it if it isn't there yet
+    pub fn set(&mut self, key: &str, value: Value) {
+        match self.get_mut(key) {
+            Some(v) => *v = value,
+            None => self.0.push((key.to_string(), value)),
+        }
+    }
+}
+
+/// Parses a whole document, returning the root dict
+pub fn parse(xml: &str) -> Result<Dict> {
+    let mut p = Parser { s: xml, pos: 0 };
+    p.skip_ws();
+    if p.rest().starts_with("<?") {
+        p.skip_past("?>")?;
+    }
+    let (name, _) = p.tag()?;
+    if name != "plist" {
+        bail!("expected <plist>, found <{name}>");
+    }
+    match p.value()? {
+        Value::Dict(dict) => Ok(dict),
+        _ => bail!("expected a dict at the root of the plist"),
+    }
+}
+
+/// Writes a whole document with the root dict
+pub fn to_xml(root: &Dict) -> String {
+    let mut out = String::from(r#"<?xml version="1.0"?><plist version="1.0" gjver="2.0"><dict>"#);
+    write_entries(&mut out, root);
+    out += "</dict></plist>";
+    out
+}
+
+fn write_entries(out: &mut String, dict: &Dict) {
+    for (k, v) in &dict.0 {
+        *out += &format!("<k>{}</k>", escape(k));
+        match v {
+            Value::Dict(d) => {
+                *out += "<d>";
+                write_entries(out, d);
+                *out += "</d>";
+            }
+            Value::String(s) => *out += &format!("<s>{}</s>", escape(s)),
+            Value::Integer(i) => *out += &format!("<i>{i}</i>"),
+            Value::Real(r) => *out += &format!("<r>{r}</r>"),
+            Value::True => *out += "<t />",
+            Value::False => *out += "<f />",
+        }
+    }
+}
+
+struct Parser<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.s[self.pos..]
+    }
+
+    fn skip_ws(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn skip_past(&mut self, pat: &str) -> Result<()> {
+        let i = self
+            .rest()
+            .find(pat)
+            .ok_or_else(|| anyhow!("expected {pat:?}"))?;
+        self.pos += i + pat.len();
+        Ok(())
+    }
+
+    /// Reads an opening tag, returns its name and whether it closes itself
+    fn tag(&mut self) -> Result<(&'a str, bool)> {
+        self.skip_ws();
+        if !self.rest().starts_with('<') {
+            bail!("expected a tag at byte {}", self.pos);
+        }
+        let start = self.pos + 1;
+        self.skip_past(">")?;
+        let inner = &self.s[start..self.pos - 1];
+        let closed = inner.ends_with('/');
+        let name = inner
+            .trim_end_matches('/')
+            .split_whitespace()
+            .next()
+            .unwrap_or_default();
+        Ok((name, closed))
+    }
+
+    /// Text up to the closing `</name>`
+    fn text(&mut self, name: &str) -> Result<String> {
+        let close = format!("</{name}>");
+        let i = self
+            .rest()
+            .find(&close)
+            .ok_or_else(|| anyhow!("unclosed <{name}> at byte {}", self.pos))?;
+        let text = unescape(&self.rest()[..i]);
+        self.pos += i + close.len();
+        Ok(text)
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        let (name, closed) = self.tag()?;
+        let text = |p: &mut Self| {
+            if closed {
+                Ok(String::new())
+            } else {
+                p.text(name)
+            }
+        };
+        Ok(match name {
+            "d" | "dict" if closed => Value::Dict(Dict::default()),
+            "d" | "dict" => Value::Dict(self.entries(name)?),
+            "s" => Value::String(text(self)?),
+            "i" => Value::Integer(text(self)?),
+            "r" => Value::Real(text(self)?),
+            "t" | "true" => Value::True,
+            "f" | "false" => Value::False,
+            _ => bail!("unknown value <{name}> at byte {}", self.pos),
+        })
+    }
+
+    fn entries(&mut self, name: &str) -> Result<Dict> {
+        let close = format!("</{name}>");
+        let mut dict = Dict::default();
+        loop {
+            self.skip_ws();
+            if self.rest().starts_with(&close) {
+                self.pos += close.len();
+                return Ok(dict);
+            }
+            let (tag, _) = self.tag()?;
+            if tag != "k" {
+                bail!("expected a key, found <{tag}> at byte {}", self.pos);
+            }
+            let key = self.text("k")?;
+            let value = self
+                .value()
+                .with_context(|| format!("invalid value for key {key:?}"))?;
+            dict.0.push((key, value));
+        }
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_values() {
+        let dict = parse(
+            r#"<?xml version="1.0"?>
+            <plist version="1.0" gjver="2.0"><dict>
+                <k>s</k><s>text</s>
+                <k>i</k><i>35</i>
+                <k>r</k><r>0.5</r>
+                <k>t</k><t />
+                <k>t2</k><t/>
+                <k>f</k><f />
+                <k>empty</k><s />
+                <k>d</k><d><k>inner</k><i>1</i></d>
+                <k>d2</k><d />
+            </dict></plist>"#,
+        )
+        .unwrap();
+        assert_eq!(dict.get_str("s"), Some("text"));
+        assert_eq!(dict.get("i"), Some(&Value::Integer("35".into())));
+        assert_eq!(dict.get("r"), Some(&Value::Real("0.5".into())));
+        assert_eq!(dict.get("t"), Some(&Value::True));
+        assert_eq!(dict.get("t2"), Some(&Value::True));
+        assert_eq!(dict.get("f"), Some(&Value::False));
+        assert_eq!(dict.get_str("empty"), Some(""));
+        let inner = Dict(vec![("inner".into(), Value::Integer("1".into()))]);
+        assert_eq!(dict.get("d"), Some(&Value::Dict(inner)));
+        assert_eq!(dict.get("d2"), Some(&Value::Dict(Dict::default())));
+    }
+
+    #[test]
+    fn unescapes_entities() {
+        let dict = parse(
+            "<plist><dict><k>a&amp;b</k>\
+            <s>&lt;x&gt; &quot;y&quot; &apos;z&apos; &amp;lt;</s></dict></plist>",
+        )
+        .unwrap();
+        assert_eq!(dict.get_str("a&b"), Some(r#"<x> "y" 'z' &lt;"#));
+    }
+
+    #[test]
+    fn round_trip() {
+        let dict = Dict(vec![
+            ("k2".into(), Value::String("<a & b>".into())),
+            ("k13".into(), Value::True),
+            ("k14".into(), Value::False),
+            ("k45".into(), Value::Integer("0".into())),
+            ("kI1".into(), Value::Real("-12.5".into())),
+            ("kI6".into(), Value::Dict(Dict::default())),
+        ]);
+        let xml = to_xml(&dict);
+        assert!(xml.contains("<k>k13</k><t />"));
+        assert!(xml.contains("<s>&lt;a &amp; b&gt;</s>"));
+        assert_eq!(parse(&xml).unwrap(), dict);
+    }
+
+    #[test]
+    fn rejects_invalid_documents() {
+        assert!(parse("<dict></dict>").is_err());
+        assert!(parse("<plist><dict><k>a</k><x>1</x></dict></plist>").is_err());
+        assert!(parse("<plist><dict><k>a</k><s>1</dict></plist>").is_err());
+        assert!(parse("<plist><dict><s>1</s></dict></plist>").is_err());
+    }
+}
@@ -1,117 +1,272 @@
-use image::DynamicImage;
 use std::fs;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use rand::{rngs::StdRng, SeedableRng};
+
+use crate::{
+    backend::{Backend, Tint},
+    checkpoint::{Accepted, Checkpoint},
+    color::{self, CHANNEL_BASE},
+    config::Config,
+    gmd::GmdLevel,
+    level::{prop, Level, Object},
+    library::Library,
+    metric, palette,
+    save::LocalLevels,
+    shape::*,
+    State, TintBuffer,
+};
 
-use crate::{shape::*, State, TintBuffer};
-
-pub const OPACITY: f32 = 0.8;
-
-const ITERATIONS: usize = 3000;
 // const SHAPES_ADJUSTED: usize = 10;
-// const ADJUSTMENTS: usize = 100;
 
+/// Candidates evaluated per draw call, the shader is built with the same value
 pub const TOTAL_SHAPES: usize = 2048;
 
-const CUTOFF: usize = 32;
-const PASSED_ON: usize = 600;
-pub const ADJUSTMENTS: usize = 24;
-
-pub const OBJ_IDS: &[u16] = &[
-    18, 19, 20, 21, 41, 48, 49, 106, 107, 110, 113, 114, 115, 123, 124, 125, 126, 127, 128, 129,
-    130, 131, 134, 151, 152, 153, 157, 158, 159, 190, 211, 225, 226, 227, 228, 229, 230, 231, 232,
-    233, 234, 235, 237, 238, 239, 240, 241, 242, 251, 259, 266, 273, 277, 278, 279, 280, 281, 282,
-    283, 284, 285, 406, 407, 408, 409, 410, 411, 412, 413, 414, 419, 420, 448, 449, 450, 451, 452,
-    498, 499, 500, 501, 503, 504, 505, 578, 579, 580, 581, 582, 583, 584, 587, 588, 589, 590, 591,
-    592, 593, 596, 597, 598, 599, 600, 601, 602, 605, 606, 607, 608, 609, 610, 611, 614, 615, 616,
-    617, 618, 619, 620, 693, 695, 697, 699, 701, 867, 868, 869, 870, 871, 872, 873, 874, 877, 878,
-    880, 881, 882, 883, 884, 885, 890, 891, 893, 894, 895, 606, 907, 908, 909, 910, 936, 937, 938,
-    939, 940, 941, 942, 1011, 1012, 1013, 1014, 1015, 1016, 1043, 1044, 1047, 104, 1062, 1063,
-    1064, 1065, 1066, 1067, 1068, 1069, 1099, 110, 1101, 1102, 1103, 1104, 1105, 1106, 1107, 1109,
-    1112, 1113, 1114, 1115, 1116, 1117, 1118, 1120, 1122, 1123, 1124, 1125, 1126, 1127, 1132, 1133,
-    1134, 1135, 1136, 1137, 1138, 1139, 1191, 1192, 1193, 1196, 1197, 1198, 1228, 1229, 1230, 1231,
-    1232, 1233, 1234, 1235, 1236, 1237, 1238, 1239, 1240, 1269, 1270, 1291, 1293, 1348, 1349, 1350,
-    1351, 1352, 1353, 1354, 1355, 1356, 1357, 1358, 1359, 1360, 1361, 1362, 1363, 1364, 1365, 1366,
-    1367, 1368, 1369, 1370, 1371, 1372, 1373, 1374, 1375, 1376, 1377, 1378, 1379, 1380, 1381, 1382,
-    1383, 1384, 1385, 1386, 1387, 1388, 1389, 1390, 1391, 1392, 1393, 1394, 1395, 1431, 1432, 1433,
-    1434, 1435, 1436, 1437, 1438, 1439, 1440, 1441, 1442, 1443, 1444, 1445, 1446, 1447, 1448, 1449,
-    1450, 1451, 1452, 1453, 1454, 1455, 1456, 1457, 1458, 1459, 1460, 1461, 1462, 1463, 1464, 1471,
-    1472, 1473, 1496, 1507, 1510, 1511, 1512, 1513, 1514, 1515, 1529, 1530, 1531, 1532, 1533, 1534,
-    1535, 1538, 1539, 1540, 1596, 1597, 1608, 1609, 1610, 1621, 1622, 1623, 1624, 1625, 1627, 1628,
-    1629, 1630, 1631, 1632, 1633, 1634, 1635, 1636, 1738, 1753, 1754, 1757, 1764, 1765, 1766, 1767,
-    1768, 1769, 1770, 1771, 1772, 1777, 1778, 1779, 1780, 1835, 1836, 1837, 1861, 1869, 1870, 1871,
-    1875, 1876, 1877, 1888,
-];
-
-pub const TARGET: &str = "seal.png";
-
-pub async fn process(state: &State, bg_color: [f32; 3]) {
-    let mut level_string = format!(";1,899,2,-29,3,975,36,1,7,255,8,0,9,0,10,0,35,{OPACITY},23,1;1,899,2,-29,3,1005,36,1,7,{},8,{},9,{},10,0,35,1,23,1000;", to_srgb(bg_color[0]) * 255.0, to_srgb(bg_color[1]) * 255.0, to_srgb(bg_color[2]) * 255.0);
-    let mut shapes = Vec::new();
-
-    for iteration in 0..ITERATIONS {
-        pollster::block_on(crate::render(
-            state,
-            &format!("./frames/anim{:04}.png", iteration),
-        ));
-        while shapes.len() < TOTAL_SHAPES {
-            let shape = Shape::new_random(state.target_size.width, state.target_size.height);
-            shapes.push(shape);
-        }
+/// Why a run ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Iterations,
+    ObjectLimit,
+    TargetError,
+    TimeLimit,
+    Plateau,
+}
 
-        //dbg!(&shapes);
-        let mut diff = test_diff(state, &shapes)
-            .into_iter()
-            .enumerate()
-            .collect::<Vec<_>>();
+impl std::fmt::Display for StopReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            StopReason::Iterations => "ran all iterations",
+            StopReason::ObjectLimit => "reached the object limit",
+            StopReason::TargetError => "reached the target error",
+            StopReason::TimeLimit => "ran out of time",
+            StopReason::Plateau => "stopped improving",
+        })
+    }
+}
 
-        for j in 0..ADJUSTMENTS {
-            diff.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
-            let mut new_shapes = vec![shapes[diff[0].0]];
-            for (i, _) in diff[..CUTOFF].iter() {
-                for _ in 0..(TOTAL_SHAPES / CUTOFF - 2) {
-                    let mut shape = shapes[*i];
-                    shape.adjust_random(j);
-                    new_shapes.push(shape);
-                }
-            }
-            while new_shapes.len() < TOTAL_SHAPES {
-                let mut shape = shapes[0];
-                shape.adjust_random(j);
-                new_shapes.push(shape);
-            }
-            //assert_eq!(new_shapes.len(), TOTAL_SHAPES);
-            shapes = new_shapes;
-            diff = test_diff(state, &shapes)
-                .into_iter()
-                .enumerate()
-                .collect::<Vec<_>>();
+/// The optimizer's progress, everything a checkpoint saves about it
+struct Run {
+    /// Candidates carried over into `iteration`
+    shapes: Vec<Shape>,
+    accepted: Vec<Accepted>,
+    error: i64,
+    last_improvement: usize,
+    /// The next iteration to run
+    iteration: usize,
+    /// Time spent before the run was resumed
+    resumed: Duration,
+    /// Objects the palette search placed before this run, they count towards the limit
+    searched: usize,
+}
+
+impl Run {
+    fn new(error: i64) -> Run {
+        Run {
+            shapes: Vec::new(),
+            accepted: Vec::new(),
+            error,
+            last_improvement: 0,
+            iteration: 0,
+            resumed: Duration::ZERO,
+            searched: 0,
         }
+    }
 
-        if diff[0].1 >= 0 {
-            continue;
+    /// Pastes what the checkpoint placed so far onto the cleared canvas
+    fn resume(
+        checkpoint: Checkpoint,
+        backend: &mut dyn Backend,
+        palette: Option<&[[f32; 3]]>,
+    ) -> Run {
+        println!("resuming at iteration {}", checkpoint.iteration);
+        // the error is kept at the scale the diffs are summed at, which depends on the canvas
+        let scale = metric::diff_scale(&checkpoint.config, backend.target_size());
+        let error = checkpoint.error as f64 * (scale / checkpoint.diff_scale) as f64;
+        for a in &checkpoint.accepted {
+            backend.paste(&a.shape, shown(&a.tint, palette));
         }
-        println!("frame {} - improvement: {}", iteration, -diff[0].1);
-        shapes[diff[0].0].paste(state, diff[0].0);
-        let tint = pollster::block_on(get_tint(state, diff[0].0));
-
-        //dbg!(shapes[diff[0].0]);
-        // dbg!(tint.map(|x| (x * 255.0) as u8));
-
-        // if iteration > 200 && diff[0].1 < -1000 {
-        //     break;
-        // }
-        //dbg!(shapes[diff[0].0]);
-
-        level_string += &shapes[diff[0].0].to_obj_string(
-            to_srgb(tint[0]),
-            to_srgb(tint[1]),
-            to_srgb(tint[2]),
-            iteration,
-        );
+        Run {
+            shapes: checkpoint.population,
+            accepted: checkpoint.accepted,
+            error: error as i64,
+            last_improvement: checkpoint.last_improvement,
+            iteration: checkpoint.iteration,
+            resumed: checkpoint.elapsed,
+            searched: checkpoint.searched,
+        }
+    }
+}
 
-        shapes = shapes[1..(PASSED_ON + 1)].to_vec();
+/// What the level really shows for an accepted tint
+fn shown(tint: &Tint, palette: Option<&[[f32; 3]]>) -> Tint {
+    match palette {
+        // already one of the channel colors
+        Some(_) => *tint,
+        None => color::realize(tint).0,
     }
+}
 
-    crate::render(state, &format!("./frames/anim{:04}.png", ITERATIONS)).await;
+/// `error` is the starting difference between the target and the cleared canvas,
+/// in the same units as the improvements, a checkpoint continues the run it was saved from
+///
+/// Fails if the checkpoint is from a different object library, a frame can't be written,
+/// or the results can't be added to the level or save file they go into
+pub async fn process(
+    backend: &mut dyn Backend,
+    config: &Config,
+    bg_color: [f32; 3],
+    error: i64,
+    library: &Library,
+    mut checkpoint: Option<Checkpoint>,
+) -> Result<()> {
+    if checkpoint
+        .as_ref()
+        .is_some_and(|c| c.objects != library.ids())
+    {
+        bail!("the object library changed since the checkpoint was saved");
+    }
+    if let Some(frames) = &config.frames {
+        fs::create_dir_all(frames)
+            .with_context(|| format!("couldn't create {}", frames.display()))?;
+    }
+
+    println!("seed: {}", config.seed);
+
+    // palette mode runs twice, first with free tints to find the colors and then with them
+    let mut palette = checkpoint.as_mut().and_then(|c| c.palette.take());
+    let mut after_search = None;
+    if let (Some(size), None) = (config.palette, &palette) {
+        let checkpoint = checkpoint.take();
+        let (colors, run) =
+            find_palette(backend, config, bg_color, error, library, checkpoint, size)?;
+        palette = Some(colors);
+        after_search = Some(run);
+    }
+    let palette = palette.as_deref();
+    if let Some(palette) = palette {
+        backend.set_palette(palette);
+    }
+
+    let mut run = match checkpoint {
+        Some(checkpoint) => Run::resume(checkpoint, backend, palette),
+        None => after_search.unwrap_or_else(|| Run::new(error)),
+    };
+    optimize(backend, config, library, &mut run, palette)?;
+    if palette.is_none() {
+        let clipped = run
+            .accepted
+            .iter()
+            .filter(|a| color::realize(&a.tint).1)
+            .count();
+        if clipped > 0 {
+            println!("{clipped} objects were tinted brighter than GD can show and got clipped");
+        }
+    }
+
+    let opacity = config.opacity;
+    let mut level = match &config.append {
+        Some(path) => fs::read_to_string(path)
+            .with_context(|| format!("couldn't read {}", path.display()))?
+            .trim_end()
+            .parse::<Level>()
+            .with_context(|| format!("invalid level to append to {}", path.display()))?,
+        None => Level::default(),
+    };
+
+    // an existing level keeps its channels, groups and background
+    let free_channel = |level: &Level| {
+        level
+            .free_color_channel()
+            .context("the level to append to has no free color channel")
+    };
+    let (channel, group) = match config.append {
+        Some(_) => (
+            free_channel(&level)?,
+            Some(match config.group {
+                Some(group) => group,
+                None => level
+                    .free_group()
+                    .context("the level to append to has no free group")?,
+            }),
+        ),
+        None => (1, config.group),
+    };
+
+    // palette colors are set on channels of their own, free tints shift red channels
+    let (channels, detail_channel) = match palette {
+        Some(palette) => {
+            let mut channels = Vec::new();
+            for (i, color) in palette.iter().enumerate() {
+                let channel = match config.append {
+                    Some(_) if i > 0 => free_channel(&level)?,
+                    _ => channel + i as u16,
+                };
+                let y = 975.0 - 30.0 * i as f32;
+                let color = color::trigger_rgb(*color);
+                level.push(Object::color_trigger(-29.0, y, channel, color, opacity));
+                channels.push(channel);
+            }
+            (channels, None)
+        }
+        None => {
+            let red = color::trigger_rgb(CHANNEL_BASE);
+            level.push(Object::color_trigger(-29.0, 975.0, channel, red, opacity));
+            // detail sprites are tinted independently, so they get a red channel of their own
+            let detail_channel = match library.objects.iter().any(|o| o.detail.is_some()) {
+                true => {
+                    let channel = match config.append {
+                        Some(_) => free_channel(&level)?,
+                        None => 2,
+                    };
+                    level.push(Object::color_trigger(-29.0, 945.0, channel, red, opacity));
+                    Some(channel)
+                }
+                false => None,
+            };
+            (vec![channel], detail_channel)
+        }
+    };
+    if config.append.is_none() && !config.no_background {
+        level.push(Object::color_trigger(
+            -29.0,
+            1005.0,
+            1000,
+            color::trigger_rgb(bg_color),
+            1.0,
+        ));
+    }
+    let object = |accepted: &Accepted| {
+        let tint = palette.is_none().then_some(&accepted.tint);
+        let mut object =
+            accepted
+                .shape
+                .to_object(tint, accepted.iteration, &config.placement, library);
+        let (channel, detail_channel) = match palette {
+            Some(palette) => (
+                channels[palette::nearest(palette, accepted.tint.main)],
+                Some(channels[palette::nearest(palette, accepted.tint.detail)]),
+            ),
+            None => (channels[0], detail_channel),
+        };
+        let def = &library.objects[accepted.shape.img_index];
+        let (channel_prop, _, _) = def.color.props();
+        object.set(channel_prop, channel);
+        if let (Some(_), Some(detail_channel)) = (&def.detail, detail_channel) {
+            let (channel_prop, _, _) = def.color.other().props();
+            object.set(channel_prop, detail_channel);
+        }
+        if let Some(layer) = config.editor_layer {
+            object.set(prop::EDITOR_LAYER, layer);
+        }
+        if let Some(group) = group {
+            object.set(prop::GROUPS, group);
+        }
+        object
+    };
+    for accepted in &run.accepted {
+        level.push(object(accepted));
+    }
 
     // let shape = Shape {
     //     img_index: 47,
@@ -135,7 +290,7 @@ pub async fn process(state: &State, bg_color: [f32; 3]) {
     //     mapping.await.unwrap();
 
     //     let data: crate::TintBuffer =
-    //         *bytemuck::from_bytes(&buffer_slice.get_mapped_range().to_vec());
+    //         *bytemuck::from_bytes(&buffer_slice.get_mapped_range());
     //     state.tint_buffer.unmap();
     //     dbg!(&data);
     // });
@@ -233,7 +388,238 @@ pub async fn process(state: &State, bg_color: [f32; 3]) {
 
     // dbg!(test_diff(state, shape2, target, spritesheet));
 
-    fs::write("./levelstring.txt", level_string).expect("Unable to write file");
+    let level_string = level.to_string();
+    let gmd = GmdLevel {
+        name: config.name.clone(),
+        description: config.description.clone(),
+        creator: config.creator.clone(),
+        song: config.song,
+        custom_song: config.custom_song,
+        level_string: level_string.clone(),
+    };
+    if let Some(path) = &config.gmd {
+        fs::write(path, gmd.to_xml()?)
+            .with_context(|| format!("couldn't write {}", path.display()))?;
+    }
+    if let Some(path) = &config.save_file {
+        let mut save = LocalLevels::load_or_new(path)?;
+        save.insert(gmd.to_dict()?)?;
+        save.save(path)?;
+    }
+    fs::write(&config.level, level_string)
+        .with_context(|| format!("couldn't write {}", config.level.display()))?;
+    Ok(())
+}
+
+/// The first run of palette mode, its tints clustered into at most `size` colors
+///
+/// It gets half of the time and object limits, the run it returns for the colors starts
+/// with what it used up
+fn find_palette(
+    backend: &mut dyn Backend,
+    config: &Config,
+    bg_color: [f32; 3],
+    error: i64,
+    library: &Library,
+    checkpoint: Option<Checkpoint>,
+    size: usize,
+) -> Result<(Vec<[f32; 3]>, Run)> {
+    println!("looking for a palette of {size} colors");
+    let mut run = match checkpoint {
+        Some(checkpoint) => Run::resume(checkpoint, backend, None),
+        None => Run::new(error),
+    };
+    let start = Instant::now();
+    optimize(backend, config, library, &mut run, None)?;
+
+    let tints = run
+        .accepted
+        .iter()
+        .flat_map(|a| {
+            let detail = library.objects[a.shape.img_index].detail.is_some();
+            std::iter::once(a.tint.main).chain(detail.then_some(a.tint.detail))
+        })
+        .collect::<Vec<_>>();
+    let palette = palette::cluster(&tints, size, config.seed);
+    println!("found {} colors, running again with them", palette.len());
+    backend.clear(bg_color);
+    let next = Run {
+        resumed: run.resumed + start.elapsed(),
+        searched: run.accepted.len(),
+        ..Run::new(error)
+    };
+    Ok((palette, next))
+}
+
+/// Places shapes until one of the config's stop conditions hits, tints are snapped to
+/// `palette` when there is one, fails if a frame can't be written
+fn optimize(
+    backend: &mut dyn Backend,
+    config: &Config,
+    library: &Library,
+    run: &mut Run,
+    palette: Option<&[[f32; 3]]>,
+) -> Result<()> {
+    // the first run of palette mode only looks for colors, with half the limits
+    let searching = palette.is_none() && config.palette.is_some();
+    let frames = config.frames.as_ref().filter(|_| !searching);
+    let max_objects = match searching {
+        true => config.max_objects.map(|max| max.div_ceil(2)),
+        false => config.max_objects,
+    };
+    let time_limit = match searching {
+        true => config.time_limit.map(|limit| limit / 2),
+        false => config.time_limit,
+    };
+    let start = Instant::now();
+    let (pixels, scale) = {
+        let size = backend.target_size();
+        let scale = metric::diff_scale(config, size);
+        (size.width as f64 * size.height as f64, scale)
+    };
+    let mean_error = |error: i64| metric::error_units(error as f64, scale) / pixels;
+    let elapsed = || run.resumed + start.elapsed();
+    let first = run.iteration;
+    let mut stop = StopReason::Iterations;
+    let placement = &config.placement;
+
+    for iteration in first..config.iterations {
+        let reason = if max_objects.is_some_and(|max| run.searched + run.accepted.len() >= max) {
+            Some(StopReason::ObjectLimit)
+        } else if config
+            .target_error
+            .is_some_and(|target| mean_error(run.error) <= target)
+        {
+            Some(StopReason::TargetError)
+        } else if time_limit.is_some_and(|limit| elapsed() >= limit) {
+            Some(StopReason::TimeLimit)
+        } else if config
+            .plateau
+            .is_some_and(|(_, rounds)| iteration - run.last_improvement >= rounds)
+        {
+            Some(StopReason::Plateau)
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            stop = reason;
+            break;
+        }
+
+        if let Some(path) = &config.checkpoint {
+            if iteration > first && iteration % config.checkpoint_every == 0 {
+                let checkpoint = Checkpoint {
+                    config: config.clone(),
+                    iteration,
+                    error: run.error,
+                    diff_scale: scale,
+                    last_improvement: run.last_improvement,
+                    elapsed: elapsed(),
+                    searched: run.searched,
+                    accepted: run.accepted.clone(),
+                    population: run.shapes.clone(),
+                    objects: library.ids(),
+                    palette: palette.map(<[_]>::to_vec),
+                };
+                if let Err(e) = checkpoint.save(path) {
+                    eprintln!("warning: {e:#}");
+                }
+            }
+        }
+        run.iteration = iteration + 1;
+
+        let mut rng = iteration_rng(config.seed, iteration);
+        if let Some(frames) = frames {
+            backend.render(&frames.join(format!("anim{:04}.png", iteration)))?;
+        }
+        let mut shapes = std::mem::take(&mut run.shapes);
+        while shapes.len() < TOTAL_SHAPES {
+            let size = backend.target_size();
+            let shape = Shape::new_random(&mut rng, size.width, size.height, library, placement);
+            shapes.push(shape);
+        }
+
+        //dbg!(&shapes);
+        let mut diff = backend
+            .test_diff(&shapes)
+            .into_iter()
+            .enumerate()
+            .collect::<Vec<_>>();
+
+        for j in 0..config.adjustments {
+            diff.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+            let mut new_shapes = vec![shapes[diff[0].0]];
+            for (i, _) in diff[..config.cutoff].iter() {
+                for _ in 0..(TOTAL_SHAPES / config.cutoff - 2) {
+                    let mut shape = shapes[*i];
+                    shape.adjust_random(&mut rng, j, config.adjustments, library, placement);
+                    new_shapes.push(shape);
+                }
+            }
+            while new_shapes.len() < TOTAL_SHAPES {
+                let mut shape = shapes[0];
+                shape.adjust_random(&mut rng, j, config.adjustments, library, placement);
+                new_shapes.push(shape);
+            }
+            //assert_eq!(new_shapes.len(), TOTAL_SHAPES);
+            shapes = new_shapes;
+            diff = backend
+                .test_diff(&shapes)
+                .into_iter()
+                .enumerate()
+                .collect::<Vec<_>>();
+        }
+
+        if diff[0].1 >= 0 {
+            run.shapes = shapes;
+            continue;
+        }
+        let improvement = metric::error_units(-diff[0].1 as f64, scale);
+        println!("frame {} - improvement: {:.0}", iteration, improvement);
+        run.error += diff[0].1 as i64;
+        if config
+            .plateau
+            .is_none_or(|(min, _)| improvement > min as f64)
+        {
+            run.last_improvement = iteration + 1;
+        }
+        let tint = backend.get_tint(diff[0].0);
+        let tint = match palette {
+            Some(palette) => Tint {
+                main: palette[palette::nearest(palette, tint.main)],
+                detail: palette[palette::nearest(palette, tint.detail)],
+            },
+            None => tint,
+        };
+        let a = Accepted {
+            iteration,
+            shape: shapes[diff[0].0],
+            tint,
+        };
+        backend.paste(&a.shape, shown(&a.tint, palette));
+        run.accepted.push(a);
+
+        run.shapes = shapes[1..(config.passed_on + 1)].to_vec();
+    }
+
+    if let Some(frames) = frames {
+        backend.render(&frames.join(format!("anim{:04}.png", run.iteration)))?;
+    }
+
+    println!(
+        "{stop} after {} iterations, placed {} objects in {:.1?}, mean error {:.2}",
+        run.iteration,
+        run.accepted.len(),
+        elapsed(),
+        mean_error(run.error)
+    );
+    Ok(())
+}
+
+/// Every iteration gets its own stream derived from the run's seed, so a run can be
+/// reproduced (or picked up again) from any iteration
+pub fn iteration_rng(seed: u64, iteration: usize) -> StdRng {
+    StdRng::seed_from_u64(seed ^ (iteration as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15))
 }
 
 pub fn test_diff(state: &State, shapes: &[Shape]) -> Vec<i32> {
@@ -257,19 +643,24 @@ pub async fn get_image_diff(state: &State) -> [i32; TOTAL_SHAPES] {
     state.device.poll(wgpu::Maintain::Wait);
     mapping.await.unwrap();
 
-    let data: TintBuffer = *bytemuck::from_bytes(&buffer_slice.get_mapped_range().to_vec());
+    let data: TintBuffer = *bytemuck::from_bytes(&buffer_slice.get_mapped_range());
     state.tint_buffer.unmap();
     data.diff
 }
 
-pub async fn get_tint(state: &State, index: usize) -> [f32; 3] {
+pub async fn get_tint(state: &State, index: usize) -> Tint {
     let buffer_slice = state.tint_buffer.slice(..);
 
     let mapping = buffer_slice.map_async(wgpu::MapMode::Read);
     state.device.poll(wgpu::Maintain::Wait);
     mapping.await.unwrap();
 
-    let data: TintBuffer = *bytemuck::from_bytes(&buffer_slice.get_mapped_range().to_vec());
+    let data: TintBuffer = *bytemuck::from_bytes(&buffer_slice.get_mapped_range());
     state.tint_buffer.unmap();
-    data.tint[index].map(|x| x as f32 / data.counts[index] as f32)
+    // shapes without a detail sprite never add to its count
+    let color = |tint: [u32; 3], count: u32| tint.map(|x| x as f32 / count.max(1) as f32);
+    Tint {
+        main: color(data.tint[index], data.counts[index]),
+        detail: color(data.detail_tint[index], data.detail_counts[index]),
+    }
 }
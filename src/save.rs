@@ -0,0 +1,244 @@
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use base64::Engine;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::gmd::BASE64;
+use crate::plist::{self, Dict, Value};
+
+/// Every byte of a save file is xored with this before anything else
+const XOR_KEY: u8 = 11;
+
+/// `CCLocalLevels.dat`, the save file holding the created levels (the Windows format,
+/// xored, base64 and gzip wrapped plist)
+#[derive(Debug, Default)]
+pub struct LocalLevels {
+    root: Dict,
+}
+
+impl LocalLevels {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<LocalLevels> {
+        let path = path.as_ref();
+        let data = fs::read(path).with_context(|| format!("couldn't read {}", path.display()))?;
+        LocalLevels::decode(&data).with_context(|| format!("couldn't decode {}", path.display()))
+    }
+
+    /// Like `load`, but starts an empty save if there is no file yet
+    pub fn load_or_new<P: AsRef<Path>>(path: P) -> Result<LocalLevels> {
+        match path.as_ref().exists() {
+            true => LocalLevels::load(path),
+            false => Ok(LocalLevels::default()),
+        }
+    }
+
+    /// Writes the save, keeping the previous file next to it as `.bak`
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        if path.exists() {
+            fs::copy(path, path.with_extension("dat.bak"))
+                .context("couldn't back up the save file")?;
+        }
+        fs::write(path, self.encode()?)
+            .with_context(|| format!("couldn't write {}", path.display()))
+    }
+
+    pub fn decode(data: &[u8]) -> Result<LocalLevels> {
+        let data = data.iter().map(|b| b ^ XOR_KEY).collect::<Vec<_>>();
+        let data = std::str::from_utf8(&data)?.trim_end_matches(['\0', '\n', '\r', ' ']);
+
+        let mut xml = String::new();
+        GzDecoder::new(&BASE64.decode(data)?[..]).read_to_string(&mut xml)?;
+        Ok(LocalLevels {
+            root: plist::parse(&xml)?,
+        })
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(plist::to_xml(&self.root).as_bytes())?;
+        let data = BASE64.encode(encoder.finish()?);
+        Ok(data.bytes().map(|b| b ^ XOR_KEY).collect())
+    }
+
+    /// The levels in the order the editor lists them, newest first
+    pub fn levels(&self) -> Vec<&Dict> {
+        let Some(Value::Dict(list)) = self.root.get("LLM_01") else {
+            return Vec::new();
+        };
+        (0..)
+            .map_while(|i| match list.get(&format!("k_{i}")) {
+                Some(Value::Dict(level)) => Some(level),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Updates the level with the same name, or adds it at the top of the list
+    pub fn insert(&mut self, level: Dict) -> Result<()> {
+        let name = level.get_str("k2").map(str::to_string);
+        let mut levels = self.levels().into_iter().cloned().collect::<Vec<_>>();
+        match levels
+            .iter_mut()
+            .find(|l| name.is_some() && l.get_str("k2") == name.as_deref())
+        {
+            // keep whatever else the game stored about the level
+            Some(existing) => {
+                for (k, v) in level.0 {
+                    existing.set(&k, v);
+                }
+            }
+            None => levels.insert(0, level),
+        }
+
+        if self.root.get("LLM_01").is_none() {
+            self.root
+                .set("LLM_01", Value::Dict(Dict(vec![("_isArr".into(), Value::True)])));
+        }
+        let Some(Value::Dict(list)) = self.root.get_mut("LLM_01") else {
+            return Err(anyhow!("LLM_01 isn't a dict"));
+        };
+        list.0.retain(|(k, _)| !k.starts_with("k_"));
+        list.0.extend(
+            levels
+                .into_iter()
+                .enumerate()
+                .map(|(i, level)| (format!("k_{i}"), Value::Dict(level))),
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn level(name: &str, data: &str) -> Dict {
+        Dict(vec![
+            ("kCEK".into(), Value::Integer("4".into())),
+            ("k2".into(), Value::String(name.into())),
+            ("k4".into(), Value::String(data.into())),
+        ])
+    }
+
+    fn names(save: &LocalLevels) -> Vec<&str> {
+        save.levels()
+            .into_iter()
+            .map(|l| l.get_str("k2").unwrap())
+            .collect()
+    }
+
+    /// A save like the game writes, with a version entry next to the level list
+    fn synthetic() -> LocalLevels {
+        let mut save = LocalLevels::default();
+        save.insert(level("first", "H4sIAAAA")).unwrap();
+        save.insert(level("second", "H4sIAAAB")).unwrap();
+        save.root.set("LLM_02", Value::Integer("35".into()));
+        save
+    }
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("gdeometrize-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let save = synthetic();
+        let data = save.encode().unwrap();
+
+        // xored base64 of a gzipped plist
+        let unxored = data.iter().map(|b| b ^ XOR_KEY).collect::<Vec<_>>();
+        let mut xml = String::new();
+        GzDecoder::new(&BASE64.decode(unxored).unwrap()[..])
+            .read_to_string(&mut xml)
+            .unwrap();
+        assert!(xml.starts_with("<?xml"));
+        assert!(xml.contains("<k>k2</k><s>second</s>"));
+
+        let decoded = LocalLevels::decode(&data).unwrap();
+        assert_eq!(decoded.root, save.root);
+        assert_eq!(names(&decoded), ["second", "first"]);
+    }
+
+    #[test]
+    fn decode_ignores_trailing_padding() {
+        let mut data = synthetic().encode().unwrap();
+        data.extend([b'\n' ^ XOR_KEY, XOR_KEY, XOR_KEY]);
+        assert_eq!(LocalLevels::decode(&data).unwrap().root, synthetic().root);
+    }
+
+    #[test]
+    fn decode_rejects_garbage() {
+        assert!(LocalLevels::decode(b"not a save").is_err());
+    }
+
+    #[test]
+    fn insert_puts_new_levels_on_top() {
+        let mut save = synthetic();
+        save.insert(level("third", "")).unwrap();
+        assert_eq!(names(&save), ["third", "second", "first"]);
+        let Some(Value::Dict(list)) = save.root.get("LLM_01") else {
+            panic!("no level list");
+        };
+        assert_eq!(list.get("_isArr"), Some(&Value::True));
+        let keys = list.0.iter().map(|(k, _)| k.as_str()).collect::<Vec<_>>();
+        assert_eq!(keys, ["_isArr", "k_0", "k_1", "k_2"]);
+        assert_eq!(save.root.get("LLM_02"), Some(&Value::Integer("35".into())));
+    }
+
+    #[test]
+    fn insert_replaces_a_level_with_the_same_name() {
+        let mut save = synthetic();
+        let mut updated = level("first", "H4sIAAAC");
+        updated.0.remove(0);
+        save.insert(updated).unwrap();
+
+        assert_eq!(names(&save), ["second", "first"]);
+        let first = save.levels()[1];
+        assert_eq!(first.get_str("k4"), Some("H4sIAAAC"));
+        // entries the new level doesn't have are kept
+        assert_eq!(first.get("kCEK"), Some(&Value::Integer("4".into())));
+    }
+
+    #[test]
+    fn save_backs_up_the_previous_file() {
+        let dir = temp_dir("save");
+        let path = dir.join("CCLocalLevels.dat");
+        let backup = dir.join("CCLocalLevels.dat.bak");
+
+        let mut save = synthetic();
+        save.save(&path).unwrap();
+        assert!(!backup.exists());
+        let first = fs::read(&path).unwrap();
+
+        save.insert(level("third", "")).unwrap();
+        save.save(&path).unwrap();
+        assert_eq!(fs::read(&backup).unwrap(), first);
+        let loaded = LocalLevels::load(&path).unwrap();
+        assert_eq!(names(&loaded), ["third", "second", "first"]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn missing_save_starts_empty() {
+        let dir = temp_dir("missing");
+        let path = dir.join("CCLocalLevels.dat");
+        assert!(LocalLevels::load(&path).is_err());
+
+        let mut save = LocalLevels::load_or_new(&path).unwrap();
+        assert!(save.levels().is_empty());
+        save.insert(level("new", "")).unwrap();
+        save.save(&path).unwrap();
+        assert_eq!(names(&LocalLevels::load(&path).unwrap()), ["new"]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,11 +1,16 @@
 // Vertex shader
 
 struct VertexInput {
-    [[location(0)]] position: vec2<i32>;
+    [[location(0)]] position: vec2<f32>;
     [[location(1)]] tex_coords: vec2<f32>;
     // [[location(2)]] tint: vec4<f32>;
     [[location(2)]] tint_index: i32;
     [[location(3)]] target_coords: vec2<f32>;
+    [[location(4)]] detail_coords: vec2<f32>;
+    [[location(5)]] detail_rect: vec4<f32>;
+    [[location(6)]] page: i32;
+    [[location(7)]] detail_page: i32;
+    [[location(8)]] tex_rect: vec4<f32>;
 };
 
 struct VertexOutput {
@@ -14,15 +19,25 @@ struct VertexOutput {
     // [[location(1)]] tint: vec4<f32>;
     [[location(1)]] tint_index: i32;
     [[location(2)]] target_coords: vec2<f32>;
+    [[location(3)]] detail_coords: vec2<f32>;
+    [[location(4)]] detail_rect: vec4<f32>;
+    [[location(5)]] page: i32;
+    [[location(6)]] detail_page: i32;
+    [[location(7)]] tex_rect: vec4<f32>;
 };
 
-let total_shapes = 2048;
-
 let factor = 1000.0;
 struct Tint {
     tint: array<array<atomic<u32>, 3>, total_shapes>;
     counts: array<atomic<u32>, total_shapes>;
+    detail_tint: array<array<atomic<u32>, 3>, total_shapes>;
+    detail_counts: array<atomic<u32>, total_shapes>;
     opacity: f32;
+    // how much the edge difference counts next to the color difference
+    edge_weight: f32;
+    // linear colors the tints are snapped to in palette mode, none otherwise
+    palette_size: u32;
+    palette: array<array<f32, 3>, max_palette>;
 
     diff: array<atomic<i32>, total_shapes>;
 };
@@ -32,6 +47,9 @@ struct Tint {
 var t_target: texture_2d<f32>;
 [[group(1), binding(1)]]
 var s_target: sampler;
+// how much every target pixel counts, in the red channel
+[[group(1), binding(2)]]
+var t_mask: texture_2d<f32>;
 
 
 [[group(2), binding(0)]]
@@ -51,30 +69,52 @@ fn vs_main(
     out.tex_coords = model.tex_coords;
     let size = textureDimensions(t_target);
     out.clip_position = vec4<f32>(
-        (f32(model.position.x) / f32(size.x)) * 2.0 - 1.0, 
-        -((f32(model.position.y) / f32(size.y)) * 2.0 - 1.0),
+        (model.position.x / f32(size.x)) * 2.0 - 1.0,
+        -((model.position.y / f32(size.y)) * 2.0 - 1.0),
         0.0, 1.0
     );
     out.tint_index = model.tint_index;
     out.target_coords = model.target_coords;
+    out.detail_coords = model.detail_coords;
+    out.detail_rect = model.detail_rect;
+    out.page = model.page;
+    out.detail_page = model.detail_page;
+    out.tex_rect = model.tex_rect;
     return out;
 }
 
 [[group(0), binding(0)]]
-var t_diffuse: texture_2d<f32>;
+var t_diffuse: texture_2d_array<f32>;
 [[group(0), binding(1)]]
 var s_diffuse: sampler;
 
+// the detail sprite is centered on the main one and can be smaller, so it's sampled
+// from its own rect and is transparent outside of it
+fn sample_detail(in: VertexOutput) -> vec4<f32> {
+    let uv = in.detail_rect.xy + clamp(in.detail_coords, vec2<f32>(0.0), vec2<f32>(1.0)) * in.detail_rect.zw;
+    let inside = f32(in.detail_rect.z > 0.0
+        && in.detail_coords.x > 0.0
+        && in.detail_coords.x < 1.0
+        && in.detail_coords.y > 0.0
+        && in.detail_coords.y < 1.0);
+    // the sheet has no mipmaps, this only lets it be sampled in non-uniform control flow
+    return textureSampleLevel(t_diffuse, s_diffuse, uv, in.detail_page, 0.0) * inside;
+}
+
 [[stage(fragment)]]
 fn fs_find_avg_color(in: VertexOutput) -> [[location(0)]] vec4<f32> {
-    let tex = textureSample(t_diffuse, s_diffuse, in.tex_coords);
+    let tex = textureSample(t_diffuse, s_diffuse, in.tex_coords, in.page);
+    let detail = sample_detail(in);
     let target = textureSample(t_target, s_target, in.target_coords);
+    // pixels count towards the tint as much as they do towards the diff
+    let weight = textureSample(t_mask, s_target, in.target_coords).r;
     let fac = f32(in.target_coords.x > 0.0
         && in.target_coords.x < 1.0 
         && in.target_coords.y > 0.0 
-        && in.target_coords.y < 1.0);
-       
-    let a = tex.a;
+        && in.target_coords.y < 1.0) * weight;
+
+    // only the part of the main sprite the detail doesn't cover shows
+    let a = tex.a * (1.0 - detail.a);
     atomicAdd(&tint.counts[in.tint_index], u32(a * factor * fac));
     let f = f32(tex.r > 0.0);
     atomicAdd(&tint.tint[in.tint_index][0], u32(f * a * (target.r / tex.r) * factor * fac));
@@ -85,44 +125,245 @@ fn fs_find_avg_color(in: VertexOutput) -> [[location(0)]] vec4<f32> {
     let f = f32(tex.b > 0.0);
     atomicAdd(&tint.tint[in.tint_index][2], u32(f * a * (target.b / tex.b) * factor * fac));
     
-    
+    let a = detail.a;
+    atomicAdd(&tint.detail_counts[in.tint_index], u32(a * factor * fac));
+    let f = f32(detail.r > 0.0);
+    atomicAdd(&tint.detail_tint[in.tint_index][0], u32(f * a * (target.r / detail.r) * factor * fac));
+
+    let f = f32(detail.g > 0.0);
+    atomicAdd(&tint.detail_tint[in.tint_index][1], u32(f * a * (target.g / detail.g) * factor * fac));
+
+    let f = f32(detail.b > 0.0);
+    atomicAdd(&tint.detail_tint[in.tint_index][2], u32(f * a * (target.b / detail.b) * factor * fac));
+
     return vec4<f32>(0.0);
 }
 
-fn color_diff(p1: vec3<f32>, p2: vec3<f32>) -> f32 {
+// the color metrics, same as `metric::Metric::diff`
+
+fn redmean_diff(p1: vec3<f32>, p2: vec3<f32>) -> f32 {
     let d: vec3<f32> = p1 - p2;
     let rdash = (p1.r + p2.r) / 2.0;
     return sqrt((2.0 + rdash) * d.r * d.r + 4.0 * d.g * d.g + (3.0 - rdash) * d.b * d.b);
 }
 
+fn cbrt(x: f32) -> f32 {
+    return sign(x) * pow(abs(x), 1.0 / 3.0);
+}
+
+fn lab_f(t: f32) -> f32 {
+    if (t > 216.0 / 24389.0) {
+        return cbrt(t);
+    }
+    return (24389.0 / 27.0 * t + 16.0) / 116.0;
+}
+
+fn lab(c: vec3<f32>) -> vec3<f32> {
+    let x = (0.4124 * c.r + 0.3576 * c.g + 0.1805 * c.b) / 0.95047;
+    let y = 0.2126 * c.r + 0.7152 * c.g + 0.0722 * c.b;
+    let z = (0.0193 * c.r + 0.1192 * c.g + 0.9505 * c.b) / 1.08883;
+    let fx = lab_f(x);
+    let fy = lab_f(y);
+    let fz = lab_f(z);
+    return vec3<f32>(116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz));
+}
+
+fn oklab(c: vec3<f32>) -> vec3<f32> {
+    let l = cbrt(0.41222147 * c.r + 0.53633255 * c.g + 0.051445993 * c.b);
+    let m = cbrt(0.2119035 * c.r + 0.6806995 * c.g + 0.10739696 * c.b);
+    let s = cbrt(0.08830246 * c.r + 0.28171885 * c.g + 0.6299787 * c.b);
+    return vec3<f32>(
+        0.21045426 * l + 0.7936178 * m - 0.004072047 * s,
+        1.9779985 * l - 2.4285922 * m + 0.4505937 * s,
+        0.025904037 * l + 0.78277177 * m - 0.80867577 * s,
+    );
+}
+
+// radians in a degree
+let degree = 0.017453292;
+
+fn hue_degrees(b: f32, a: f32) -> f32 {
+    let h = atan2(b, a) / degree;
+    return h - 360.0 * floor(h / 360.0);
+}
+
+fn pow7(x: f32) -> f32 {
+    return x * x * x * x * x * x * x;
+}
+
+fn ciede2000(lab1: vec3<f32>, lab2: vec3<f32>) -> f32 {
+    let c_bar = (length(lab1.yz) + length(lab2.yz)) / 2.0;
+    let g = 0.5 * (1.0 - sqrt(pow7(c_bar) / (pow7(c_bar) + pow7(25.0))));
+    let a1 = (1.0 + g) * lab1.y;
+    let a2 = (1.0 + g) * lab2.y;
+    let c1 = length(vec2<f32>(a1, lab1.z));
+    let c2 = length(vec2<f32>(a2, lab2.z));
+    let h1 = hue_degrees(lab1.z, a1);
+    let h2 = hue_degrees(lab2.z, a2);
+    let chromatic = c1 * c2 != 0.0;
+
+    var dh = h2 - h1;
+    if (!chromatic) {
+        dh = 0.0;
+    } else if (h2 - h1 > 180.0) {
+        dh = h2 - h1 - 360.0;
+    } else if (h2 - h1 < -180.0) {
+        dh = h2 - h1 + 360.0;
+    }
+    let dl = lab2.x - lab1.x;
+    let dc = c2 - c1;
+    let dh = 2.0 * sqrt(c1 * c2) * sin(dh / 2.0 * degree);
+
+    let l_bar = (lab1.x + lab2.x) / 2.0;
+    let c_bar = (c1 + c2) / 2.0;
+    var h_bar = (h1 + h2) / 2.0;
+    if (!chromatic) {
+        h_bar = h1 + h2;
+    } else if (abs(h1 - h2) > 180.0 && h1 + h2 < 360.0) {
+        h_bar = (h1 + h2 + 360.0) / 2.0;
+    } else if (abs(h1 - h2) > 180.0) {
+        h_bar = (h1 + h2 - 360.0) / 2.0;
+    }
+    let t = 1.0 - 0.17 * cos((h_bar - 30.0) * degree) + 0.24 * cos(2.0 * h_bar * degree)
+        + 0.32 * cos((3.0 * h_bar + 6.0) * degree) - 0.20 * cos((4.0 * h_bar - 63.0) * degree);
+    let d_theta = 30.0 * exp(-((h_bar - 275.0) / 25.0) * ((h_bar - 275.0) / 25.0));
+    let rc = 2.0 * sqrt(pow7(c_bar) / (pow7(c_bar) + pow7(25.0)));
+    let sl = 1.0 + 0.015 * (l_bar - 50.0) * (l_bar - 50.0) / sqrt(20.0 + (l_bar - 50.0) * (l_bar - 50.0));
+    let sc = 1.0 + 0.045 * c_bar;
+    let sh = 1.0 + 0.015 * c_bar * t;
+    let rt = -sin(2.0 * d_theta * degree) * rc;
+
+    let l = dl / sl;
+    let c = dc / sc;
+    let h = dh / sh;
+    return sqrt(max(l * l + c * c + h * h + rt * c * h, 0.0));
+}
+
+// `metric` is prepended together with `total_shapes`, it's `metric::Metric::id`
+fn color_diff(p1: vec3<f32>, p2: vec3<f32>) -> f32 {
+    if (metric == 0u) {
+        let d = p1 - p2;
+        return dot(d, d) / 3.0;
+    }
+    if (metric == 2u) {
+        return distance(lab(p1), lab(p2)) / 100.0;
+    }
+    if (metric == 3u) {
+        return ciede2000(lab(p1), lab(p2)) / 100.0;
+    }
+    if (metric == 4u) {
+        return distance(oklab(p1), oklab(p2));
+    }
+    return redmean_diff(p1, p2);
+}
+
+// same as `palette::nearest`
+fn snap_to_palette(color: vec3<f32>) -> vec3<f32> {
+    var best = color;
+    var best_distance = 1e30;
+    for (var i: u32 = 0u; i < tint.palette_size; i = i + 1u) {
+        let p = vec3<f32>(tint.palette[i][0], tint.palette[i][1], tint.palette[i][2]);
+        let d = dot(p - color, p - color);
+        if (d < best_distance) {
+            best = p;
+            best_distance = d;
+        }
+    }
+    return best;
+}
+
+// the current canvas with the main sprite and the detail drawn over it
+fn blend(color: vec4<f32>, detail: vec4<f32>, current: vec3<f32>) -> vec3<f32> {
+    let next = color.rgb * color.a + current * (1.0 - color.a);
+    return detail.rgb * detail.a + next * (1.0 - detail.a);
+}
+
+fn luma(c: vec3<f32>) -> f32 {
+    return dot(c, vec3<f32>(0.2126, 0.7152, 0.0722));
+}
+
+// how much the interpolated coordinates change from one pixel to the next
+struct Coords {
+    tex: vec2<f32>;
+    detail: vec2<f32>;
+    target: vec2<f32>;
+};
+
+// the luminance of the target, the current canvas and the canvas with the shape drawn
+// over it, `o` pixels away from this fragment
+fn lumas(in: VertexOutput, dx: Coords, dy: Coords, o: vec2<f32>, t: vec4<f32>, dt: vec4<f32>) -> vec3<f32> {
+    var n = in;
+    n.tex_coords = in.tex_coords + dx.tex * o.x + dy.tex * o.y;
+    n.detail_coords = in.detail_coords + dx.detail * o.x + dy.detail * o.y;
+    let uv = in.target_coords + dx.target * o.x + dy.target * o.y;
+
+    // the quad ends where the main sprite does
+    let r = in.tex_rect;
+    let inside = f32(all(n.tex_coords > r.xy) && all(n.tex_coords < r.xy + r.zw));
+    let color = textureSampleLevel(t_diffuse, s_diffuse, n.tex_coords, n.page, 0.0) * t * inside;
+    let detail = sample_detail(n) * dt * inside;
+
+    let target = textureSampleLevel(t_target, s_target, uv, 0.0).rgb;
+    let current = textureSampleLevel(t_current, s_current, uv, 0.0).rgb;
+    return vec3<f32>(luma(target), luma(current), luma(blend(color, detail, current)));
+}
+
 [[stage(fragment)]]
 fn fs_find_diff(in: VertexOutput) -> [[location(0)]] vec4<f32> {
 
     let c = f32(tint.counts[in.tint_index]) / factor;
-        
-    var t = vec4<f32>(
-        (f32(tint.tint[in.tint_index][0]) / factor) / c,
-        (f32(tint.tint[in.tint_index][1]) / factor) / c,
-        (f32(tint.tint[in.tint_index][2]) / factor) / c,
-        tint.opacity,
-    );
+    let dc = f32(tint.detail_counts[in.tint_index]) / factor;
+    // a sum without any count is 0 too, this only keeps it from becoming NaN
+    let cm = max(c, 1.0 / factor);
+    let dcm = max(dc, 1.0 / factor);
+
+    let t = snap_to_palette(vec3<f32>(
+        (f32(tint.tint[in.tint_index][0]) / factor) / cm,
+        (f32(tint.tint[in.tint_index][1]) / factor) / cm,
+        (f32(tint.tint[in.tint_index][2]) / factor) / cm,
+    ));
+    let dt = snap_to_palette(vec3<f32>(
+        (f32(tint.detail_tint[in.tint_index][0]) / factor) / dcm,
+        (f32(tint.detail_tint[in.tint_index][1]) / factor) / dcm,
+        (f32(tint.detail_tint[in.tint_index][2]) / factor) / dcm,
+    ));
+    let t = vec4<f32>(t, tint.opacity);
+    let dt = vec4<f32>(dt, tint.opacity);
 
-    let color = textureSample(t_diffuse, s_diffuse, in.tex_coords) * t;
+    let color = textureSample(t_diffuse, s_diffuse, in.tex_coords, in.page) * t;
+    let detail = sample_detail(in) * dt;
 
     let target = textureSample(t_target, s_target, in.target_coords).rgb;
     let current = textureSample(t_current, s_current, in.target_coords).rgb;
 
-    let next = color.rgb * color.a + current * (1.0 - color.a);
+    let next = blend(color, detail, current);
 
-    let diff = color_diff(target, next) - color_diff(target, current);
-        
-    let fac = f32(c > 0.0 
+    var diff = color_diff(target, next) - color_diff(target, current);
+
+    // the coordinates are affine across a triangle, so these are exact
+    let dx = Coords(dpdx(in.tex_coords), dpdx(in.detail_coords), dpdx(in.target_coords));
+    let dy = Coords(dpdy(in.tex_coords), dpdy(in.detail_coords), dpdy(in.target_coords));
+    let size = vec2<f32>(textureDimensions(t_target));
+    let interior = all(abs(in.target_coords - 0.5) < 0.5 - 1.0 / size);
+    if (tint.edge_weight > 0.0 && interior) {
+        // central differences of the luminance, for the target, current and next canvas
+        let gx = (lumas(in, dx, dy, vec2<f32>(1.0, 0.0), t, dt)
+            - lumas(in, dx, dy, vec2<f32>(-1.0, 0.0), t, dt)) / 2.0;
+        let gy = (lumas(in, dx, dy, vec2<f32>(0.0, 1.0), t, dt)
+            - lumas(in, dx, dy, vec2<f32>(0.0, -1.0), t, dt)) / 2.0;
+        let edges_current = length(vec2<f32>(gx.x - gx.y, gy.x - gy.y));
+        let edges_next = length(vec2<f32>(gx.x - gx.z, gy.x - gy.z));
+        diff = diff + tint.edge_weight * (edges_next - edges_current);
+    }
+
+    let fac = f32((c > 0.0 || dc > 0.0)
         && in.target_coords.x > 0.0
         && in.target_coords.x < 1.0 
         && in.target_coords.y > 0.0
         && in.target_coords.y < 1.0);
         
-    atomicAdd(&tint.diff[in.tint_index], i32(255.0 * diff * fac));
+    let weight = textureSample(t_mask, s_target, in.target_coords).r;
+    atomicAdd(&tint.diff[in.tint_index], i32(floor(diff * weight * fac * diff_scale + 0.5)));
     
 
     return vec4<f32>(0.0);
@@ -130,18 +371,29 @@ fn fs_find_diff(in: VertexOutput) -> [[location(0)]] vec4<f32> {
 
 [[stage(fragment)]]
 fn fs_main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
-    let color = textureSample(t_diffuse, s_diffuse, in.tex_coords);
-    
-    let c = f32(tint.counts[in.tint_index]);
+    let c = max(f32(tint.counts[in.tint_index]), 1.0);
+    let dc = max(f32(tint.detail_counts[in.tint_index]), 1.0);
     
-    var tint = vec4<f32>(
+    let t = vec4<f32>(
         f32(tint.tint[in.tint_index][0]) / c,
         f32(tint.tint[in.tint_index][1]) / c,
         f32(tint.tint[in.tint_index][2]) / c,
         tint.opacity,
     );
+    let dt = vec4<f32>(
+        f32(tint.detail_tint[in.tint_index][0]) / dc,
+        f32(tint.detail_tint[in.tint_index][1]) / dc,
+        f32(tint.detail_tint[in.tint_index][2]) / dc,
+        tint.opacity,
+    );
+
+    let color = textureSample(t_diffuse, s_diffuse, in.tex_coords, in.page) * t;
+    let detail = sample_detail(in) * dt;
 
-    return color * tint;
+    // the detail drawn over the main sprite, as one color for the alpha blending
+    let a = 1.0 - (1.0 - color.a) * (1.0 - detail.a);
+    let rgb = detail.rgb * detail.a + color.rgb * color.a * (1.0 - detail.a);
+    return vec4<f32>(rgb / max(a, 0.0001), a);
 }
 
 
@@ -1,60 +1,185 @@
-use crate::Vertex;
+use std::mem::offset_of;
 
-use crate::OPACITY;
+use crate::backend::Tint;
+use crate::color::{self, CHANNEL_BASE};
+use crate::level::{prop, Object};
+use crate::library::Library;
+use crate::placement::Placement;
+use crate::Vertex;
 
 use crate::TintBuffer;
 
-use crate::process::ADJUSTMENTS;
-use crate::process::OBJ_IDS;
+use crate::palette::MAX_PALETTE;
 use crate::process::TOTAL_SHAPES;
+use crate::Size;
 use crate::State;
 
-use image::DynamicImage;
+use anyhow::{anyhow, Context, Result};
 use image::RgbaImage;
 use rand::Rng;
-use texture_packer::TexturePacker;
+use serde::{Deserialize, Serialize};
+use texture_packer::exporter::ImageExporter;
+use texture_packer::texture::Texture;
+use texture_packer::{Frame, Rect};
+use texture_packer::MultiTexturePacker;
+
+/// Narrowest working resolution `Shape::new_random` can place shapes at
+pub const MIN_WIDTH: u32 = 5;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct Shape {
     pub(crate) img_index: usize,
-    pub(crate) x: i32,
-    pub(crate) y: i32,
-    pub(crate) scale: f32,
+    /// Working pixels, snapped to what the level can store so not always whole
+    pub(crate) x: f32,
+    pub(crate) y: f32,
+    pub(crate) scale_x: f32,
+    pub(crate) scale_y: f32,
     pub(crate) rot: f32,
+    /// Mirrors the sprite before it's turned, like GD's flip properties
+    pub(crate) flip_x: bool,
+    pub(crate) flip_y: bool,
     //pub(crate) tint: Option<[f32; 4]>,
 }
 // these are the obj ids were using
 
 // and then it grabs all the images and packs them into a texture at runtime
 // no i got the thing you sent in chat a few weeks ago
-pub(crate) fn pack_textures<'a>() -> TexturePacker<'a, RgbaImage, u16> {
-    let mut packer = TexturePacker::new_skyline(TexturePackerConfig {
-        max_width: 2048,
-        max_height: 2048,
-        allow_rotation: false,
-        border_padding: 0,
-        texture_padding: 2,
-        texture_extrusion: 0,
-        trim: false,
-        texture_outlines: false,
-    });
-
-    for id in OBJ_IDS {
-        let texture = image::open(&format!("objects/{}/main.png", id))
-            .unwrap()
-            .into_rgba8();
-        packer.pack_own(*id, texture).unwrap();
+// sprites that don't fit go on a new page
+pub(crate) fn pack_textures<'a>(
+    library: &Library,
+) -> Result<MultiTexturePacker<'a, RgbaImage, (u16, Layer)>> {
+    let mut packer = MultiTexturePacker::new_skyline(PACKER_CONFIG);
+
+    for object in &library.objects {
+        let sprites = [Some(object.main_sprite()), object.detail.as_deref()];
+        for (layer, path) in [Layer::Main, Layer::Detail].into_iter().zip(sprites) {
+            let Some(path) = path else { continue };
+            let texture = image::open(path)
+                .with_context(|| format!("couldn't read {}", path.display()))?
+                .into_rgba8();
+            packer
+                .pack_own((object.id, layer), texture)
+                .map_err(|e| anyhow!("couldn't pack {}: {e:?}", path.display()))?;
+        }
+    }
+
+    Ok(packer)
+}
+
+pub(crate) const PACKER_CONFIG: TexturePackerConfig = TexturePackerConfig {
+    max_width: 2048,
+    max_height: 2048,
+    allow_rotation: false,
+    border_padding: 0,
+    texture_padding: 2,
+    texture_extrusion: 0,
+    trim: false,
+    texture_outlines: false,
+};
+
+/// Which of an object's sprites a frame of the sheet holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Layer {
+    Main,
+    Detail,
+}
+
+/// The packed sprite sheet, shared by every backend
+pub struct Sheet {
+    /// All the same size, the GPU binds them as one texture array
+    pub(crate) pages: Vec<RgbaImage>,
+    pub(crate) size: [u32; 2],
+    /// In the library's order, like `Shape::img_index`
+    pub(crate) frames: Vec<Frame<(u16, Layer)>>,
+    pub(crate) detail_frames: Vec<Option<Frame<(u16, Layer)>>>,
+    /// The pages each object's main and detail sprites are on
+    pub(crate) sprite_pages: Vec<[u32; 2]>,
+    /// Half the size of each object in working pixels at scale 1
+    pub(crate) extents: Vec<[f32; 2]>,
+}
+
+impl Sheet {
+    /// Packs the library's sprites onto as many pages as they need
+    pub(crate) fn pack(library: &Library) -> Result<Sheet> {
+        Ok(Sheet::new(&pack_textures(library)?, library))
     }
 
-    packer
+    pub(crate) fn new(
+        packer: &MultiTexturePacker<RgbaImage, (u16, Layer)>,
+        library: &Library,
+    ) -> Sheet {
+        let pages = packer.get_pages();
+        let find = |key: (u16, Layer)| {
+            pages
+                .iter()
+                .enumerate()
+                .find_map(|(i, page)| page.get_frame(&key).map(|frame| (i as u32, frame.clone())))
+        };
+        let mut frames = Vec::new();
+        let mut detail_frames = Vec::new();
+        let mut sprite_pages = Vec::new();
+        for object in &library.objects {
+            let (page, frame) = find((object.id, Layer::Main)).unwrap();
+            let detail = find((object.id, Layer::Detail));
+            sprite_pages.push([page, detail.as_ref().map_or(0, |(page, _)| *page)]);
+            frames.push(frame);
+            detail_frames.push(detail.map(|(_, frame)| frame));
+        }
+
+        // the pages only grow as far as their sprites, a texture array needs them equal
+        let size = pages.iter().fold([0, 0], |[w, h], page| {
+            [w.max(page.width()), h.max(page.height())]
+        });
+        let pages = pages
+            .iter()
+            .map(|page| {
+                let image = ImageExporter::export(page).unwrap().into_rgba8();
+                if image.dimensions() == (size[0], size[1]) {
+                    return image;
+                }
+                let mut padded = RgbaImage::new(size[0], size[1]);
+                image::imageops::replace(&mut padded, &image, 0, 0);
+                padded
+            })
+            .collect();
+        Sheet::from_frames(pages, frames, detail_frames, sprite_pages, library)
+    }
+
+    /// A sheet that was packed before, the frames are in the library's order
+    pub(crate) fn from_frames(
+        pages: Vec<RgbaImage>,
+        frames: Vec<Frame<(u16, Layer)>>,
+        detail_frames: Vec<Option<Frame<(u16, Layer)>>>,
+        sprite_pages: Vec<[u32; 2]>,
+        library: &Library,
+    ) -> Sheet {
+        let extents = library
+            .objects
+            .iter()
+            .zip(&frames)
+            .map(|(object, frame)| object.half_extents([frame.frame.w, frame.frame.h]))
+            .collect();
+        Sheet {
+            size: [pages[0].width(), pages[0].height()],
+            pages,
+            frames,
+            detail_frames,
+            sprite_pages,
+            extents,
+        }
+    }
 }
 
 use texture_packer::TexturePackerConfig;
 use wgpu::util::DeviceExt;
 
+/// One point per corner of a shape's quad
+type Quad = [[f32; 2]; 4];
+
 impl Shape {
-    fn get_verts(&self, state: &State) -> ([[f32; 2]; 4], [[f32; 2]; 4]) {
-        let frame = state.packer.get(&OBJ_IDS[self.img_index]).unwrap();
+    /// Corner positions, texture coordinates and detail coordinates of the quad
+    fn get_verts(&self, sheet: &Sheet) -> (Quad, Quad, Quad) {
+        let frame = &sheet.frames[self.img_index];
         // get texture coords
         let tex_coords = {
             let mut top_left = (frame.frame.x, frame.frame.y);
@@ -69,30 +194,54 @@ impl Shape {
                 bottom_left = bottom_right;
                 bottom_right = tmp;
             }
-            let w = state.sheet_size[0] as f32;
-            let h = state.sheet_size[1] as f32;
+            let w = sheet.size[0] as f32;
+            let h = sheet.size[1] as f32;
             [
-                [top_left.0 as f32 / w as f32, top_left.1 as f32 / h as f32],
+                [top_left.0 as f32 / w, top_left.1 as f32 / h],
                 [
-                    bottom_left.0 as f32 / w as f32,
-                    bottom_left.1 as f32 / h as f32,
+                    bottom_left.0 as f32 / w,
+                    bottom_left.1 as f32 / h,
                 ],
                 [
-                    bottom_right.0 as f32 / w as f32,
-                    bottom_right.1 as f32 / h as f32,
+                    bottom_right.0 as f32 / w,
+                    bottom_right.1 as f32 / h,
                 ],
-                [top_right.0 as f32 / w as f32, top_right.1 as f32 / h as f32],
+                [top_right.0 as f32 / w, top_right.1 as f32 / h],
             ]
         };
 
+        // the detail sprite is centered on the main one, its coordinates run from 0 to 1
+        // across it and past that where the main sprite is bigger
+        let detail_coords = match &sheet.detail_frames[self.img_index] {
+            Some(detail) => {
+                let sx = frame.frame.w as f32 / detail.frame.w as f32;
+                let sy = frame.frame.h as f32 / detail.frame.h as f32;
+                [[0.0, 0.0], [0.0, 1.0], [1.0, 1.0], [1.0, 0.0]]
+                    .map(|[u, v]: [f32; 2]| [0.5 + (u - 0.5) * sx, 0.5 + (v - 0.5) * sy])
+            }
+            None => [[0.0; 2]; 4],
+        };
+
+        // flipping swaps the texture coordinates of opposite corners
+        let flip = |mut coords: Quad| {
+            if self.flip_x {
+                coords.swap(0, 3);
+                coords.swap(1, 2);
+            }
+            if self.flip_y {
+                coords.swap(0, 1);
+                coords.swap(2, 3);
+            }
+            coords
+        };
+        let (tex_coords, detail_coords) = (flip(tex_coords), flip(detail_coords));
+
         // get positions
-        let w = frame.frame.w as f32 / 2.0;
-        let h = frame.frame.h as f32 / 2.0;
+        let [w, h] = sheet.extents[self.img_index];
         let positions = [[-w, -h], [-w, h], [w, h], [w, -h]];
 
         // scale
-        let scale = self.scale;
-        let positions = positions.map(|p| [p[0] as f32 * scale, p[1] as f32 * scale]);
+        let positions = positions.map(|p| [p[0] * self.scale_x, p[1] * self.scale_y]);
 
         // rotate
         let rot = self.rot;
@@ -104,12 +253,52 @@ impl Shape {
 
         // translate
         let positions = positions.map(|p| {
-            let x = p[0] + self.x as f32;
-            let y = p[1] + self.y as f32;
+            let x = p[0] + self.x;
+            let y = p[1] + self.y;
             [x, y]
         });
 
-        (positions, tex_coords)
+        (positions, tex_coords, detail_coords)
+    }
+
+    /// The two triangles of the shape's quad, positions are in target pixels
+    pub(crate) fn get_vertices(
+        &self,
+        sheet: &Sheet,
+        target_size: &Size,
+        tint_index: usize,
+    ) -> [Vertex; 6] {
+        let (positions, tex_coords, detail_coords) = self.get_verts(sheet);
+        let (w, h) = (sheet.size[0] as f32, sheet.size[1] as f32);
+        let rect = |f: Rect| [f.x as f32 / w, f.y as f32 / h, f.w as f32 / w, f.h as f32 / h];
+        let tex_rect = rect(sheet.frames[self.img_index].frame);
+        // an empty rect marks shapes without a detail sprite
+        let detail_rect = match &sheet.detail_frames[self.img_index] {
+            Some(detail) => rect(detail.frame),
+            None => [0.0; 4],
+        };
+
+        let [page, detail_page] = sheet.sprite_pages[self.img_index];
+
+        let v = (0..4)
+            .map(|i| (positions[i], tex_coords[i], detail_coords[i]))
+            .map(|(p, t, d)| Vertex {
+                position: p,
+                tex_coords: [t[0], t[1]],
+                tint_index: tint_index as i32,
+                detail_coords: d,
+                detail_rect,
+                page: page as i32,
+                detail_page: detail_page as i32,
+                tex_rect,
+                target_coords: [
+                    p[0] / target_size.width as f32,
+                    p[1] / target_size.height as f32,
+                ],
+            })
+            .collect::<Vec<_>>();
+
+        [v[3], v[0], v[1], v[3], v[1], v[2]]
     }
 
     pub(crate) fn test_diff(shapes: &[Shape], state: &State, encoder: &mut wgpu::CommandEncoder) {
@@ -121,6 +310,8 @@ impl Shape {
         //     )
         // }
 
+        let mut palette = [[0.0; 3]; MAX_PALETTE];
+        palette[..state.palette.len()].copy_from_slice(&state.palette);
         state.queue.write_buffer(
             &state.tint_buffer,
             0,
@@ -128,33 +319,22 @@ impl Shape {
             bytemuck::cast_slice(&[TintBuffer {
                 tint: [[0, 0, 0]; TOTAL_SHAPES],
                 counts: [0; TOTAL_SHAPES],
-                opacity: OPACITY,
+                detail_tint: [[0, 0, 0]; TOTAL_SHAPES],
+                detail_counts: [0; TOTAL_SHAPES],
+                opacity: state.opacity,
+                edge_weight: state.edge_weight,
+                palette_size: state.palette.len() as u32,
+                palette,
                 diff: [0; TOTAL_SHAPES],
             }]),
         );
         let mut verteces = Vec::<Vertex>::new();
 
         for (i, shape) in shapes.iter().enumerate() {
-            let (positions, tex_coords) = shape.get_verts(state);
-
             //let c = shape.get_avg_color(state, positions, tex_coords, target, spritesheet);
             //dbg!(c);
 
-            let v = positions
-                .iter()
-                .zip(tex_coords.iter())
-                .map(|(p, t)| Vertex {
-                    position: [p[0] as i32, p[1] as i32],
-                    tex_coords: [t[0], t[1]],
-                    tint_index: i as i32,
-                    target_coords: [
-                        p[0] / state.target_size.width as f32,
-                        p[1] / state.target_size.height as f32,
-                    ],
-                })
-                .collect::<Vec<_>>();
-
-            verteces.extend([v[3], v[0], v[1], v[3], v[1], v[2]]);
+            verteces.extend(shape.get_vertices(&state.sheet, &state.target_size, i));
         }
 
         let vertex_buffer = state
@@ -192,38 +372,40 @@ impl Shape {
         render_pass("diff pass", &state.diff_pipeline);
     }
 
+    /// Draws the shape with an explicit linear tint, it's written into the first slot of the
+    /// tint buffer so any slot left from the last `test_diff` is overwritten
     pub(crate) fn paste(
         &self,
         state: &State,
         //encoder: &mut wgpu::CommandEncoder,
-        tint_index: usize,
+        tint: Tint,
     ) {
+        // the shader divides the sums by the count, so any large count keeps the precision
+        const COUNT: u32 = 1 << 20;
+        let write = |offset: usize, data: &[u8]| {
+            state
+                .queue
+                .write_buffer(&state.tint_buffer, offset as u64, data);
+        };
+        let sums = tint.main.map(|t| (t * COUNT as f32) as u32);
+        write(offset_of!(TintBuffer, tint), bytemuck::cast_slice(&sums));
+        write(offset_of!(TintBuffer, counts), bytemuck::bytes_of(&COUNT));
+        let sums = tint.detail.map(|t| (t * COUNT as f32) as u32);
+        write(offset_of!(TintBuffer, detail_tint), bytemuck::cast_slice(&sums));
+        write(offset_of!(TintBuffer, detail_counts), bytemuck::bytes_of(&COUNT));
+
         let mut encoder = state
             .device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor {
                 label: Some("Render Encoder"),
             });
-        let (positions, tex_coords) = self.get_verts(state);
-
-        let v = positions
-            .iter()
-            .zip(tex_coords.iter())
-            .map(|(p, t)| Vertex {
-                position: [p[0] as i32, p[1] as i32], // scaling?
-                tex_coords: [t[0], t[1]],
-                tint_index: tint_index as i32,
-                target_coords: [
-                    p[0] / state.target_size.width as f32,
-                    p[1] / state.target_size.height as f32,
-                ],
-            })
-            .collect::<Vec<_>>();
+        let verteces = self.get_vertices(&state.sheet, &state.target_size, 0);
 
         let vertex_buffer = state
             .device
             .create_buffer_init(&wgpu::util::BufferInitDescriptor {
                 label: Some("Vertex Buffer"),
-                contents: bytemuck::cast_slice(&[v[3], v[0], v[1], v[3], v[1], v[2]]),
+                contents: bytemuck::cast_slice(&verteces),
                 usage: wgpu::BufferUsages::VERTEX,
             });
 
@@ -253,45 +435,141 @@ impl Shape {
         state.queue.submit(std::iter::once(encoder.finish()));
     }
 
-    pub(crate) fn new_random(width: u32, height: u32) -> Shape {
-        let mut rng = rand::thread_rng();
-        let x = rng.gen_range(0..width) as i32;
-        let y = rng.gen_range(0..height) as i32;
-        let scale = rng.gen_range(0.1..(std::cmp::max(width, height) as f32 / 40.0));
+    /// Random shapes are scaled between 0.1 and a 40th of the working resolution, so
+    /// anything narrower than `MIN_WIDTH` leaves no scales to pick from
+    pub(crate) fn new_random<R: Rng + ?Sized>(
+        rng: &mut R,
+        width: u32,
+        height: u32,
+        library: &Library,
+        placement: &Placement,
+    ) -> Shape {
+        let x = rng.gen_range(0..width) as f32;
+        let y = rng.gen_range(0..height) as f32;
+        let max_scale = std::cmp::max(width, height) as f32 / 40.0;
+        let scale_x = rng.gen_range(0.1..max_scale);
+        let scale_y = rng.gen_range(0.1..max_scale);
+        let scale_y = if placement.uniform_scale { scale_x } else { scale_y };
         let rot = rng.gen_range(0.0..(2.0 * std::f32::consts::PI));
+        let flip_x = rng.gen();
+        let flip_y = rng.gen();
+
+        let img_index = rng.gen_range(0..library.objects.len());
+        let object = &library.objects[img_index];
 
-        Shape {
-            img_index: rng.gen_range(0..OBJ_IDS.len()) as usize,
+        let mut shape = Shape {
+            img_index,
             x,
             y,
-            scale,
-            rot,
+            scale_x: if object.scalable { scale_x } else { 1.0 },
+            scale_y: if object.scalable { scale_y } else { 1.0 },
+            rot: if object.rotatable { rot } else { 0.0 },
+            flip_x,
+            flip_y,
+        };
+        shape.snap(placement);
+        shape
+    }
+
+    pub(crate) fn adjust_random<R: Rng + ?Sized>(
+        &mut self,
+        rng: &mut R,
+        divisor: usize,
+        adjustments: usize,
+        library: &Library,
+        placement: &Placement,
+    ) {
+        let object = &library.objects[self.img_index];
+        let d = (adjustments - divisor) as f32 / adjustments as f32;
+        self.x += (rng.gen_range(-10i32..=10) as f32 * d).trunc();
+        self.y += (rng.gen_range(-10i32..=10) as f32 * d).trunc();
+        // draw anyway so the random sequence doesn't depend on the object
+        let scale_x = rng.gen_range(-0.2..0.2) * d;
+        let scale_y = rng.gen_range(-0.2..0.2) * d;
+        let scale_y = if placement.uniform_scale { scale_x } else { scale_y };
+        if object.scalable {
+            self.scale_x = (self.scale_x + scale_x).max(0.1);
+            self.scale_y = (self.scale_y + scale_y).max(0.1);
         }
+        let rot = rng.gen_range(-0.5..0.5) * d;
+        if object.rotatable {
+            self.rot += rot;
+        }
+        // flips are a big jump, so they get rarer as the adjustments settle
+        if rng.gen::<f32>() < 0.1 * d {
+            self.flip_x = !self.flip_x;
+        }
+        if rng.gen::<f32>() < 0.1 * d {
+            self.flip_y = !self.flip_y;
+        }
+        self.snap(placement);
     }
 
-    pub(crate) fn adjust_random(&mut self, divisor: usize) {
-        let d = (ADJUSTMENTS - divisor) as f32 / ADJUSTMENTS as f32;
-        self.x += (rand::thread_rng().gen_range(-10i32..=10) as f32 * d) as i32;
-        self.y += (rand::thread_rng().gen_range(-10i32..=10) as f32 * d) as i32;
-        self.scale += rand::thread_rng().gen_range(-0.2..0.2) * d;
-        if self.scale < 0.1 {
-            self.scale = 0.1;
+    /// Moves the shape to where the level will really put it, so the diff is taken on that
+    fn snap(&mut self, placement: &Placement) {
+        let placed = placement.place(self.x, self.y, self.rot, [self.scale_x, self.scale_y]);
+        let (x, y, rot, [scale_x, scale_y]) = placement.unplace(&placed);
+        // going back and forth isn't exact, so only what got rounded is taken over
+        let precision = placement.precision;
+        if precision.position > 0.0 {
+            (self.x, self.y) = (x, y);
+        }
+        if precision.rotation > 0.0 {
+            self.rot = rot;
+        }
+        if precision.scale > 0.0 {
+            (self.scale_x, self.scale_y) = (scale_x, scale_y);
         }
-        self.rot += rand::thread_rng().gen_range(-0.5..0.5) * d;
     }
 
-    pub(crate) fn to_obj_string(self, r: f32, g: f32, b: f32, layer: usize) -> String {
-        let (h, s, v) = rgb_to_hsv(r, g, b);
-        let hsv_string = format!("{}a{}a{}a0a0", h, s, v);
-        let scale = 1.0;
-        format!(
-            "1,{},2,{},3,{},6,{},32,{},41,1,43,{hsv_string},21,1,22,2,25,{layer},24,-1;",
-            OBJ_IDS[self.img_index],
-            (self.x as f32) * 0.5 * scale,
-            -(self.y as f32) * 0.5 * scale,
-            self.rot * 180.0 / std::f32::consts::PI,
-            self.scale * scale,
-        )
+    /// `tint` is linear, the object gets it as HSV shifts of the red color channels, see
+    /// `color`. Without one the object shows its channels' colors as they are
+    pub(crate) fn to_object(
+        self,
+        tint: Option<&Tint>,
+        layer: usize,
+        placement: &Placement,
+        library: &Library,
+    ) -> Object {
+        let def = &library.objects[self.img_index];
+        let hsv = |color: [f32; 3]| color::solve(CHANNEL_BASE, color).hsv;
+        let scale = [self.scale_x, self.scale_y];
+        let placed = placement.place(self.x, self.y, self.rot, scale);
+        let mut object = Object::new()
+            .with(prop::ID, def.id)
+            .with(prop::X, placed.x)
+            .with(prop::Y, placed.y);
+        if self.flip_x {
+            object.set(prop::FLIP_X, 1);
+        }
+        // a mirrored placement already flips every sprite once
+        if self.flip_y != placed.flip_y {
+            object.set(prop::FLIP_Y, 1);
+        }
+        object.set(prop::ROTATION, placed.rotation);
+        if placement.uniform_scale {
+            object.set(prop::SCALE, placed.scale_x);
+        } else {
+            object.set(prop::SCALE_X, placed.scale_x);
+            object.set(prop::SCALE_Y, placed.scale_y);
+        }
+        if let Some(tint) = tint {
+            let (_, hsv_enabled, hsv_prop) = def.color.props();
+            object = object
+                .with(hsv_enabled, 1)
+                .with(hsv_prop, hsv(tint.main));
+            if def.detail.is_some() {
+                let (_, hsv_enabled, hsv_prop) = def.color.other().props();
+                object = object
+                    .with(hsv_enabled, 1)
+                    .with(hsv_prop, hsv(tint.detail));
+            }
+        }
+        object
+            .with(prop::MAIN_COLOR, 1)
+            .with(prop::DETAIL_COLOR, 2)
+            .with(prop::Z_ORDER, layer)
+            .with(prop::Z_LAYER, -1)
     }
 }
 
@@ -301,57 +579,11 @@ impl Shape {
 //     (x * cos - y * sin, x * sin + y * cos)
 // }
 
-pub(crate) fn max_float(a: f32, b: f32) -> f32 {
-    if a > b {
-        a
-    } else {
-        b
-    }
-}
-pub(crate) fn min_float(a: f32, b: f32) -> f32 {
-    if a < b {
-        a
-    } else {
-        b
-    }
-}
-
 pub(crate) fn to_srgb(v: f32) -> f32 {
     let sv = if v <= 0.0031308 {
         12.92 * v
     } else {
         1.055 * v.powf(1.0 / 2.4) - 0.055
     };
-    if sv > 1.0 {
-        1.0
-    } else if sv < 0.0 {
-        0.0
-    } else {
-        sv
-    }
-}
-
-pub(crate) fn rgb_to_hsv(r: f32, g: f32, b: f32) -> (i32, f32, f32) {
-    let max = max_float(r, max_float(g, b));
-    let min = min_float(r, min_float(g, b));
-    let v = max;
-
-    let d = max - min;
-    let s = if max == 0.0 { 0.0 } else { d / max };
-
-    let h = if max == min {
-        0.0 // achromatic
-    } else {
-        (if max == r {
-            (g - b) / d + (if g < b { 6.0 } else { 0.0 })
-        } else if max == g {
-            (b - r) / d + 2.0
-        } else {
-            (r - g) / d + 4.0
-        }) / 6.0
-    };
-    if h.is_nan() || s.is_nan() || v.is_nan() {
-        panic!("NaN did done with R: {}, G: {}, B: {}", r, g, b)
-    }
-    ((h * 360.0) as i32, s, v)
+    sv.clamp(0.0, 1.0)
 }
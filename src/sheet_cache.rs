@@ -0,0 +1,190 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use texture_packer::{Frame, Rect};
+
+use crate::library::Library;
+use crate::shape::{Layer, Sheet, PACKER_CONFIG};
+
+/// Bump when the way sheets are packed or stored changes
+const VERSION: u32 = 2;
+
+/// The frame table stored next to the sheet's pages
+#[derive(Serialize, Deserialize)]
+struct Frames {
+    pages: usize,
+    frames: Vec<CachedFrame>,
+    detail_frames: Vec<Option<CachedFrame>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedFrame {
+    page: u32,
+    rect: [u32; 4],
+    rotated: bool,
+}
+
+/// Loads the sheet for the library from `dir`, or packs it and stores it there
+///
+/// Sheets are stored under a hash of the sprites and the packer config, so a changed
+/// sprite or object selection packs a new one
+///
+/// Only packing itself can fail, a cache that can't be read or written is skipped
+pub fn load_or_pack(dir: &Path, library: &Library) -> Result<Sheet> {
+    let key = match sheet_hash(library) {
+        Ok(key) => format!("{key:016x}"),
+        Err(e) => {
+            eprintln!("warning: not caching the sprite sheet: {e:#}");
+            return Sheet::pack(library);
+        }
+    };
+    let page_path = |page: usize| dir.join(format!("{key}-{page}.png"));
+    let frames_path = dir.join(format!("{key}.json"));
+
+    if frames_path.is_file() {
+        match load(page_path, &frames_path, library) {
+            Ok(sheet) => return Ok(sheet),
+            Err(e) => eprintln!("warning: packing the sprite sheet again: {e:#}"),
+        }
+    }
+
+    let sheet = Sheet::pack(library)?;
+    if let Err(e) = store(dir, page_path, &frames_path, &sheet) {
+        eprintln!("warning: couldn't cache the sprite sheet: {e:#}");
+    }
+    Ok(sheet)
+}
+
+fn load(
+    page_path: impl Fn(usize) -> PathBuf,
+    frames_path: &Path,
+    library: &Library,
+) -> Result<Sheet> {
+    let data = fs::read_to_string(frames_path)
+        .with_context(|| format!("couldn't read {}", frames_path.display()))?;
+    let cached: Frames = serde_json::from_str(&data)
+        .with_context(|| format!("invalid frame table {}", frames_path.display()))?;
+    if cached.frames.len() != library.objects.len()
+        || cached.detail_frames.len() != library.objects.len()
+    {
+        bail!("{} doesn't match the objects", frames_path.display());
+    }
+    let pages = (0..cached.pages)
+        .map(|page| {
+            let path = page_path(page);
+            Ok(image::open(&path)
+                .with_context(|| format!("couldn't read {}", path.display()))?
+                .into_rgba8())
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let size = pages.first().map(|page| page.dimensions());
+    if size.is_none() || pages.iter().any(|page| Some(page.dimensions()) != size) {
+        bail!("the cached pages of {} don't match", frames_path.display());
+    }
+    let on_page = |frame: &CachedFrame| {
+        if frame.page as usize >= pages.len() {
+            bail!("{} refers to a missing page", frames_path.display());
+        }
+        Ok(frame.page)
+    };
+    let sprite_pages = cached
+        .frames
+        .iter()
+        .zip(&cached.detail_frames)
+        .map(|(main, detail)| Ok([on_page(main)?, detail.as_ref().map_or(Ok(0), on_page)?]))
+        .collect::<Result<Vec<_>>>()?;
+
+    let frame = |id: u16, layer: Layer, cached: &CachedFrame| {
+        let [x, y, w, h] = cached.rect;
+        Frame {
+            key: (id, layer),
+            frame: Rect::new(x, y, w, h),
+            rotated: cached.rotated,
+            trimmed: false,
+            source: Rect::new(0, 0, w, h),
+        }
+    };
+    let ids = library.objects.iter().map(|o| o.id);
+    let frames = ids
+        .clone()
+        .zip(&cached.frames)
+        .map(|(id, f)| frame(id, Layer::Main, f))
+        .collect();
+    let detail_frames = ids
+        .zip(&cached.detail_frames)
+        .map(|(id, f)| f.as_ref().map(|f| frame(id, Layer::Detail, f)))
+        .collect();
+    Ok(Sheet::from_frames(
+        pages,
+        frames,
+        detail_frames,
+        sprite_pages,
+        library,
+    ))
+}
+
+fn store(
+    dir: &Path,
+    page_path: impl Fn(usize) -> PathBuf,
+    frames_path: &Path,
+    sheet: &Sheet,
+) -> Result<()> {
+    fs::create_dir_all(dir).with_context(|| format!("couldn't create {}", dir.display()))?;
+    let cached = |page: u32, frame: &Frame<(u16, Layer)>| CachedFrame {
+        page,
+        rect: [frame.frame.x, frame.frame.y, frame.frame.w, frame.frame.h],
+        rotated: frame.rotated,
+    };
+    let frames = Frames {
+        pages: sheet.pages.len(),
+        frames: sheet
+            .frames
+            .iter()
+            .zip(&sheet.sprite_pages)
+            .map(|(f, [page, _])| cached(*page, f))
+            .collect(),
+        detail_frames: sheet
+            .detail_frames
+            .iter()
+            .zip(&sheet.sprite_pages)
+            .map(|(f, [_, page])| f.as_ref().map(|f| cached(*page, f)))
+            .collect(),
+    };
+    for (i, page) in sheet.pages.iter().enumerate() {
+        let path = page_path(i);
+        page.save(&path)
+            .with_context(|| format!("couldn't write {}", path.display()))?;
+    }
+    // written last, a sheet only counts as cached once its frame table exists
+    fs::write(frames_path, serde_json::to_string(&frames)?)
+        .with_context(|| format!("couldn't write {}", frames_path.display()))
+}
+
+/// FNV-1a over everything that ends up in the sheet
+fn sheet_hash(library: &Library) -> Result<u64> {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    let mut feed = |bytes: &[u8]| {
+        for b in bytes {
+            hash = (hash ^ *b as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    };
+    feed(&VERSION.to_le_bytes());
+    feed(format!("{PACKER_CONFIG:?}").as_bytes());
+    for object in &library.objects {
+        feed(&object.id.to_le_bytes());
+        for sprite in [Some(object.main_sprite()), object.detail.as_deref()] {
+            match sprite {
+                Some(path) => {
+                    let data = fs::read(path)
+                        .with_context(|| format!("couldn't read {}", path.display()))?;
+                    feed(&(data.len() as u64).to_le_bytes());
+                    feed(&data);
+                }
+                None => feed(&[0]),
+            }
+        }
+    }
+    Ok(hash)
+}
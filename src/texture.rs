@@ -24,13 +24,76 @@ impl Texture {
         img: &image::DynamicImage,
         format: TextureFormat,
     ) -> Result<Self> {
-        let rgba = img.to_rgba8();
-        let dimensions = img.dimensions();
+        Self::from_bytes(
+            device,
+            queue,
+            format,
+            img.dimensions(),
+            1,
+            &img.to_rgba8(),
+            wgpu::TextureViewDimension::D2,
+        )
+    }
+
+    /// A linear float image, kept at full precision
+    ///
+    /// 32 bit float textures can't be filtered, so the sampler has to be a non-filtering one
+    pub fn from_linear(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        img: &image::Rgba32FImage,
+    ) -> Result<Self> {
+        Self::from_bytes(
+            device,
+            queue,
+            TextureFormat::Rgba32Float,
+            img.dimensions(),
+            1,
+            bytemuck::cast_slice(img.as_raw()),
+            wgpu::TextureViewDimension::D2,
+        )
+    }
+
+    /// Images of the same size as the layers of one texture array
+    pub fn from_pages(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        pages: &[image::RgbaImage],
+        format: TextureFormat,
+    ) -> Result<Self> {
+        let dimensions = pages[0].dimensions();
+        if pages.iter().any(|page| page.dimensions() != dimensions) {
+            bail!("texture array pages differ in size");
+        }
+        let data = pages
+            .iter()
+            .flat_map(|page| page.as_raw().iter().copied())
+            .collect::<Vec<_>>();
+        Self::from_bytes(
+            device,
+            queue,
+            format,
+            dimensions,
+            pages.len() as u32,
+            &data,
+            wgpu::TextureViewDimension::D2Array,
+        )
+    }
 
+    /// Uploads `layers` tightly packed images of `format` pixels, one after the other
+    fn from_bytes(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        format: TextureFormat,
+        (width, height): (u32, u32),
+        layers: u32,
+        data: &[u8],
+        dimension: wgpu::TextureViewDimension,
+    ) -> Result<Self> {
         let size = wgpu::Extent3d {
-            width: dimensions.0,
-            height: dimensions.1,
-            depth_or_array_layers: 1,
+            width,
+            height,
+            depth_or_array_layers: layers,
         };
         let texture = device.create_texture(&wgpu::TextureDescriptor {
             label: None,
@@ -42,6 +105,7 @@ impl Texture {
             usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
         });
 
+        let pixel_size = format.describe().block_size as u32;
         queue.write_texture(
             wgpu::ImageCopyTexture {
                 aspect: wgpu::TextureAspect::All,
@@ -49,31 +113,20 @@ impl Texture {
                 mip_level: 0,
                 origin: wgpu::Origin3d::ZERO,
             },
-            &rgba,
+            data,
             wgpu::ImageDataLayout {
                 offset: 0,
-                bytes_per_row: std::num::NonZeroU32::new(4 * dimensions.0),
-                rows_per_image: std::num::NonZeroU32::new(dimensions.1),
+                bytes_per_row: std::num::NonZeroU32::new(pixel_size * width),
+                rows_per_image: std::num::NonZeroU32::new(height),
             },
             size,
         );
 
-        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
-            address_mode_u: wgpu::AddressMode::Repeat,
-            address_mode_v: wgpu::AddressMode::Repeat,
-            address_mode_w: wgpu::AddressMode::Repeat,
-            mag_filter: wgpu::FilterMode::Nearest,
-            min_filter: wgpu::FilterMode::Nearest,
-            mipmap_filter: wgpu::FilterMode::Nearest,
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(dimension),
             ..Default::default()
         });
-
-        Ok(Self {
-            texture,
-            view,
-            sampler,
-        })
+        Self::from_texture(device, texture, view)
     }
 
     pub fn from_texture(